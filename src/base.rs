@@ -1,11 +1,90 @@
+use std::collections::BTreeMap;
+use std::path::Path;
 use std::process::exit;
 
-use deploy::{Config, parse_spec_or_exit};
+use lithos_shim::ContainerConfig;
+use serde_json::to_string_pretty;
 
+use deploy::{Config, Spec, Stage, parse_spec_or_exit};
+use deploy::vars::register_secrets;
+use inner::nice_cmdline;
+use redact::redact;
+
+
+#[derive(Debug, Default, StructOpt)]
+pub struct VarsOptions {
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct ShowOptions {
+    #[structopt(help="name of the deployment to show", name="NAME")]
+    pub deployment: String,
+    #[structopt(help="print deployment as JSON", long="json")]
+    pub json: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Process<'a> {
+    config_path: &'a str,
+    container: &'a str,
+    vagga_container: String,
+    #[serde(skip)]
+    command_line: String,
+    executable: &'a str,
+    arguments: &'a [String],
+    workdir: &'a Path,
+    memory_limit: u64,
+    fileno_limit: u64,
+    cpu_shares: usize,
+    volumes: BTreeMap<&'a str, String>,
+    environ: &'a BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+struct StageInfo<'a> {
+    name: &'a str,
+    tool: &'a str,
+    when: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Deployment<'a> {
+    name: &'a str,
+    version: &'a str,
+    daemons: BTreeMap<&'a str, Process<'a>>,
+    commands: BTreeMap<&'a str, Process<'a>>,
+    script: Vec<StageInfo<'a>>,
+    on_failure: Vec<StageInfo<'a>>,
+}
+
+
+fn print_vars(config: &Config) {
+    println!("Declared variables:");
+    for (name, decl) in &config.variables {
+        println!("    {:10} [{}]", name, decl.summary());
+        if let Some(ref description) = decl.description {
+            println!("        {}", description);
+        }
+    }
+}
 
 pub fn main(config: Config) -> ! {
     let spec = parse_spec_or_exit(config);
 
+    if spec.config.variables.len() > 0 {
+        print_vars(&spec.config);
+    }
+    if spec.config.targets.len() > 0 {
+        println!("Available targets:");
+        for (name, target) in &spec.config.targets {
+            let stages = match target.script {
+                Some(ref script) => script.len(),
+                None => spec.config.script.len(),
+            };
+            println!("    {:10} [stages: {}, vars: {}]",
+                name, stages, target.vars.len());
+        }
+    }
     if spec.deployments.len() > 0 {
         println!("Available deployments:");
         for (name, dep) in &spec.deployments {
@@ -16,9 +95,133 @@ pub fn main(config: Config) -> ! {
     } else {
         error!("No deployments available. Add some files matching {:?}",
             format!("{}/{}",
-                spec.config.deployment_dirs,
-                spec.config.lithos_configs)
+                spec.config.deployment_dirs.glob(),
+                spec.config.lithos_configs.glob())
             .replace(|c| c == '(' || c == ')', ""));
         exit(1);
     }
 }
+
+fn process<'a>(spec: &Spec, config_path: &'a str, container: &'a str,
+    config: &'a ContainerConfig)
+    -> Process<'a>
+{
+    Process {
+        config_path, container,
+        vagga_container: format!("{}{}",
+            container, spec.config.container_suffix),
+        command_line: nice_cmdline(config),
+        executable: &config.executable,
+        arguments: &config.arguments,
+        workdir: &config.workdir,
+        memory_limit: config.memory_limit,
+        fileno_limit: config.fileno_limit,
+        cpu_shares: config.cpu_shares,
+        volumes: config.volumes.iter()
+            .map(|(path, volume)| (&path[..], format!("{:?}", volume)))
+            .collect(),
+        environ: &config.environ,
+    }
+}
+
+fn stage_info(stage: &Stage) -> StageInfo {
+    StageInfo {
+        name: stage.name(),
+        tool: stage.tool(),
+        when: stage.when().map(|c| c.to_string()),
+    }
+}
+
+fn print_stages(title: &str, stages: &[StageInfo]) {
+    if stages.is_empty() {
+        return;
+    }
+    println!("{}:", title);
+    for stage in stages {
+        match stage.when {
+            Some(ref when) => {
+                println!("    {} ({}) when {}", stage.name, stage.tool, when);
+            }
+            None => println!("    {} ({})", stage.name, stage.tool),
+        }
+    }
+}
+
+fn print_processes(title: &str, processes: &BTreeMap<&str, Process>) {
+    if processes.is_empty() {
+        return;
+    }
+    println!("{}:", title);
+    for (name, p) in processes {
+        println!("    {}", name);
+        println!("        config: {}", p.config_path);
+        println!("        container: {} (built as {})",
+            p.container, p.vagga_container);
+        println!("        command-line: {}", redact(&p.command_line));
+        println!("        workdir: {}", p.workdir.display());
+        println!("        memory-limit: {}", p.memory_limit);
+        println!("        fileno-limit: {}", p.fileno_limit);
+        println!("        cpu-shares: {}", p.cpu_shares);
+        if !p.volumes.is_empty() {
+            println!("        volumes:");
+            for (path, volume) in &p.volumes {
+                println!("            {}: {}", path, volume);
+            }
+        }
+        if !p.environ.is_empty() {
+            println!("        environ:");
+            for (key, value) in p.environ {
+                println!("            {}={}", key, redact(value));
+            }
+        }
+    }
+}
+
+pub fn show(options: ShowOptions, config: Config) -> ! {
+    let spec = parse_spec_or_exit(config);
+    register_secrets(&spec.config.variables);
+    let dep = match spec.deployments.get(&options.deployment) {
+        Some(dep) => dep,
+        None => {
+            error!("No deployment {:?} found, available: {}",
+                options.deployment,
+                spec.deployments.keys().map(|x| &x[..])
+                .collect::<Vec<_>>().join(", "));
+            exit(1);
+        }
+    };
+    let info = Deployment {
+        name: &options.deployment,
+        version: &spec.version,
+        daemons: dep.daemons.iter().map(|(name, d)| {
+            (&name[..], process(&spec, &d.config_path, &d.container,
+                                &d.config))
+        }).collect(),
+        commands: dep.commands.iter().map(|(name, c)| {
+            (&name[..], process(&spec, &c.config_path, &c.container,
+                                &c.config))
+        }).collect(),
+        script: spec.config.script.iter().map(stage_info).collect(),
+        on_failure: spec.config.on_failure.iter().map(stage_info).collect(),
+    };
+    if options.json {
+        println!("{}", redact(&to_string_pretty(&info)
+            .expect("can serialize deployment")));
+    } else {
+        println!("Deployment {:?} (version {})", info.name, info.version);
+        print_processes("Daemons", &info.daemons);
+        print_processes("Commands", &info.commands);
+        print_stages("Script", &info.script);
+        print_stages("On failure", &info.on_failure);
+    }
+    exit(0);
+}
+
+pub fn vars(_options: VarsOptions, config: Config) -> ! {
+    if config.variables.len() > 0 {
+        print_vars(&config);
+    } else {
+        println!("No variables declared, any `-D NAME=VALUE` is accepted");
+    }
+    exit(0);
+}
@@ -1,14 +1,21 @@
-use std::path::Path;
-use std::collections::BTreeMap;
+use std::env;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-use failure::{Error, err_msg};
-use quire::{parse_config, Options};
+use capturing_glob::{Pattern as GlobMatcher};
+use failure::Error;
+use quire::{parse_string, Options};
 use quire::validate::{Structure, Scalar, Enum, Nothing, Mapping, Anything};
-use trimmer::{Variable, Output, DataError};
+use quire::validate::{Sequence};
+use serde::{Deserialize, Deserializer};
+use serde_json::to_string;
+use trimmer::{Variable, Var, Output, DataError, Context};
 
+use exit::ErrorKind;
 use wark_version::MinimumVersion;
-use templates::Pattern;
+use templates::{Condition, Pattern, GlobPattern, GlobVar, Value};
 use deploy::tools;
+use deploy::includes::{Loader, is_url};
+use deploy::vars::Declaration;
 
 
 #[derive(Debug, Deserialize)]
@@ -22,21 +29,108 @@ pub enum VersionKind {
 pub enum Stage {
     Ciruela(tools::ciruela::Settings),
     VerwalterKokkupanek(tools::kokkupanek::Settings),
+    Command(tools::command::Settings),
+}
+
+impl Stage {
+    /// Name of the tool as written in config
+    pub fn tool(&self) -> &'static str {
+        match *self {
+            Stage::Ciruela(..) => "ciruela",
+            Stage::VerwalterKokkupanek(..) => "verwalter_kokkupanek",
+            Stage::Command(..) => "command",
+        }
+    }
+    /// Name of the stage, which is the name of the tool unless specified
+    pub fn name(&self) -> &str {
+        let name = match *self {
+            Stage::Ciruela(ref s) => s.name.as_ref(),
+            Stage::VerwalterKokkupanek(ref s) => s.name.as_ref(),
+            Stage::Command(ref s) => s.name.as_ref(),
+        };
+        name.map(|x| &x[..]).unwrap_or(self.tool())
+    }
+    /// Condition to run the stage, stage always runs if `None`
+    pub fn when(&self) -> Option<&Condition> {
+        match *self {
+            Stage::Ciruela(ref s) => s.when.as_ref(),
+            Stage::VerwalterKokkupanek(ref s) => s.when.as_ref(),
+            Stage::Command(ref s) => s.when.as_ref(),
+        }
+    }
+}
+
+/// Where the wark binary used inside the deploy container comes from
+#[derive(Debug, Deserialize)]
+pub enum InnerWark {
+    /// Release of the same version as the running wark from github
+    Release,
+    /// Download the binary from the url, checking sha256 sum if specified
+    Download(WarkDownload),
+    /// Copy the binary from the path (as seen inside vagga, i.e. `/work/..`)
+    Copy(String),
+    /// Copy the running binary into the project at the specified path
+    /// (relative to the project root, `vagga/wark` by default) on `update`
+    /// and use it
+    Current(Option<String>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WarkDownload {
+    pub url: String,
+    pub sha256: Option<String>,
+}
+
+/// Path to the template of vagga config, built-in template if `None`
+#[derive(Debug)]
+pub struct VaggaTemplate(pub Option<String>);
+
+/// File to cache container versions in, disabled if `None`
+#[derive(Debug)]
+pub struct VersionCache(pub Option<String>);
+
+/// Cluster-wide deploy lock, see `deploy::lock`
+#[derive(Debug, Deserialize)]
+pub struct Lock {
+    pub url: Option<Pattern>,
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Target {
+    pub script: Option<Vec<Stage>>,
+    pub on_failure: Option<Vec<Stage>>,
+    pub vars: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Deserialize, Variable)]
 pub struct Config {
     pub minimum_wark: String,
-    pub deployment_dirs: String,
-    pub lithos_configs: String,
+    pub deployment_dirs: GlobPattern,
+    pub lithos_configs: GlobPattern,
     pub default_copy: BTreeMap<String, String>,
     pub config_files_inner: String,
-    pub vagga_config: String,
+    pub vagga_config: Pattern,
+    pub vagga_template: VaggaTemplate,
+    pub inner_wark: InnerWark,
     pub container_suffix: String,
+    pub version_cache: VersionCache,
+    pub lock: Lock,
+    pub protected: Vec<String>,
     pub version: VersionKind,
     pub deployment_name: Pattern,
     pub process_name: Pattern,
     pub script: Vec<Stage>,
+    /// Stages run when a stage of the `script` fails
+    ///
+    /// Failures before the script is started (building containers, taking
+    /// the lock, resuming) exit without running these stages.
+    pub on_failure: Vec<Stage>,
+    pub variables: BTreeMap<String, Declaration>,
+    pub targets: BTreeMap<String, Target>,
+    /// Absolute path or url the config is read from
+    #[serde(skip)]
+    pub source: String,
 }
 
 
@@ -50,7 +144,22 @@ impl Config {
         .member("config_files_inner",
             Scalar::new().default("/config/deploy-*/lithos.*.yaml"))
         .member("vagga_config", Scalar::new().default("vagga/deploy.yaml"))
+        .member("vagga_template", Scalar::new().optional())
+        .member("inner_wark", Enum::new()
+            .option("Release", Nothing)
+            .option("Download", Structure::new()
+                .member("url", Scalar::new())
+                .member("sha256", Scalar::new().optional()))
+            .option("Copy", Scalar::new())
+            .option("Current", Scalar::new().optional())
+            .optional()
+            .default_tag("Release"))
         .member("container_suffix", Scalar::new().default("-deploy"))
+        .member("version_cache", Scalar::new().optional())
+        .member("lock", Structure::new()
+            .member("url", Scalar::new().optional())
+            .member("timeout", Scalar::new().default(1800)))
+        .member("protected", Sequence::new(Scalar::new()))
         .member("deployment_name", Scalar::new()
             .default("{{ patterns.deployment_dirs[1] }}"))
         .member("process_name", Scalar::new()
@@ -60,14 +169,162 @@ impl Config {
             .allow_plain())
         // TODO(tailhook)
         .member("script", Anything)
+        .member("on_failure", Anything)
+        .member("variables", Mapping::new(Scalar::new(), Structure::new()
+            .member("description", Scalar::new().optional())
+            .member("default", Scalar::new().optional())
+            .member("type", Enum::new()
+                .option("string", Nothing)
+                .option("bool", Nothing)
+                .option("integer", Nothing)
+                .allow_plain()
+                .plain_default("string"))
+            .member("values", Sequence::new(Scalar::new()))
+            .member("regex", Scalar::new().optional())
+            .member("required", Scalar::new().default(false))
+            .member("secret", Scalar::new().default(false))
+            .member("env", Scalar::new().optional())
+            .member("file", Scalar::new().optional())))
+        .member("targets", Mapping::new(Scalar::new(), Structure::new()
+            .member("script", Anything)
+            .member("on_failure", Anything)
+            .member("vars", Mapping::new(Scalar::new(), Scalar::new()))))
     }
-    pub fn parse<P: AsRef<Path>>(fname: P) -> Result<Config, Error> {
-        let cfg = parse_config(fname, &Config::validator(),
-                               &Options::default())
+    /// Parses config from a local file or url, resolving includes
+    pub fn parse(name: &str) -> Result<Config, Error> {
+        let name = if is_url(name) {
+            name.to_string()
+        } else {
+            // absolute path, so relative includes are resolved correctly
+            env::current_dir()?.join(name)
+                .to_str().expect("path is utf-8").to_string()
+        };
+        let loader = Loader::new();
+        let mut options = Options::default();
+        options.allow_include(|pos, incl, err, opt| {
+            loader.include(pos, incl, err, opt)
+        });
+        let root = format!("!*Include {}",
+            to_string(&name).expect("can serialize string"));
+        let mut cfg: Config = parse_string(&name, &root,
+                &Config::validator(), &options)
             // TODO(tailhook) fix when quire fixed
-            .map_err(|e| err_msg(format!("{}", e)))?;
+            .map_err(|e| ErrorKind::Config.error(e))?;
+        cfg.source = name.clone();
+        Config::check_patterns(&cfg)?;
+        for pattern in &cfg.protected {
+            GlobMatcher::new(pattern).map_err(|e| {
+                format_err!("protected: bad pattern {:?}: {}", pattern, e)
+            })?;
+        }
+        Config::check_stage_names(&cfg.script, "script")?;
+        Config::check_stage_names(&cfg.on_failure, "on_failure")?;
+        for (name, target) in &cfg.targets {
+            if let Some(ref script) = target.script {
+                Config::check_stage_names(script,
+                    &format!("targets.{}.script", name))?;
+            }
+            if let Some(ref script) = target.on_failure {
+                Config::check_stage_names(script,
+                    &format!("targets.{}.on_failure", name))?;
+            }
+        }
         Ok(cfg)
     }
+    /// Checks that stage names are unique
+    ///
+    /// Unnamed stages are referred by tool name, so it may repeat.
+    fn check_stage_names(script: &[Stage], key: &str) -> Result<(), Error> {
+        let mut names = BTreeSet::new();
+        for stage in script {
+            if !names.insert(stage.name()) && stage.name() != stage.tool() {
+                bail!("{}: duplicate stage name {:?}", key, stage.name());
+            }
+        }
+        Ok(())
+    }
+    /// Checks that name patterns refer only to existing variables
+    fn check_patterns(&self) -> Result<(), Error> {
+        let dirs = GlobVar::example(&self.deployment_dirs);
+        let files = GlobVar::example(&self.lithos_configs);
+        let patterns = vec![
+            ("deployment_dirs", &dirs),
+            ("lithos_configs", &files),
+        ].into_iter().collect::<BTreeMap<_, _>>();
+        let mut context = Context::new();
+        context.set("patterns", &patterns);
+        self.deployment_name.render_key("deployment_name", &context)?;
+        self.process_name.render_key("process_name", &context)?;
+        let deployment = Value(String::from("example"));
+        let mut context = Context::new();
+        context.set("deployment", &deployment);
+        self.vagga_config.render_key("vagga_config", &context)?;
+        Ok(())
+    }
+    /// Applies target: replaces scripts and adds target's variable values
+    ///
+    /// Variables passed explicitly override ones from the target. Without
+    /// a target the top-level script is used, which is required to be
+    /// non-empty if there are targets defined.
+    pub fn select_target(&mut self, target: &Option<String>,
+        vars: &mut HashMap<String, String>)
+        -> Result<(), Error>
+    {
+        self.target_vars(target, vars)?;
+        if let Some(ref name) = *target {
+            let target = self.targets.remove(name).expect("target exists");
+            if let Some(script) = target.script {
+                self.script = script;
+            }
+            if let Some(script) = target.on_failure {
+                self.on_failure = script;
+            }
+        }
+        Ok(())
+    }
+    /// Checks that target exists and adds its variables to `vars`
+    ///
+    /// Same as `select_target` but doesn't modify config.
+    pub fn target_vars(&self, target: &Option<String>,
+        vars: &mut HashMap<String, String>)
+        -> Result<(), Error>
+    {
+        let name = match *target {
+            Some(ref name) => name,
+            None if self.targets.len() > 0 && self.script.is_empty() => {
+                bail!("Please specify --target, available: {}",
+                    self.targets.keys().map(|x| &x[..])
+                    .collect::<Vec<_>>().join(", "));
+            }
+            None => return Ok(()),
+        };
+        let target = match self.targets.get(name) {
+            Some(target) => target,
+            None => {
+                bail!("No target {:?} found, available: {}", name,
+                    self.targets.keys().map(|x| &x[..])
+                    .collect::<Vec<_>>().join(", "));
+            }
+        };
+        for (key, value) in &target.vars {
+            vars.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+        Ok(())
+    }
+    /// Returns script of the target (which must exist) or top-level one
+    pub fn target_script(&self, target: &Option<String>) -> &[Stage] {
+        target.as_ref()
+            .and_then(|name| self.targets.get(name))
+            .and_then(|t| t.script.as_ref())
+            .unwrap_or(&self.script)
+    }
+    /// Returns `on_failure` of the target (which must exist) or top-level one
+    pub fn target_on_failure(&self, target: &Option<String>) -> &[Stage] {
+        target.as_ref()
+            .and_then(|name| self.targets.get(name))
+            .and_then(|t| t.on_failure.as_ref())
+            .unwrap_or(&self.on_failure)
+    }
 }
 
 impl<'render> Variable<'render> for VersionKind {
@@ -87,3 +344,96 @@ impl<'render> Variable<'render> for Stage {
         "Stage"
     }
 }
+
+impl InnerWark {
+    /// Url of the github release matching the running wark
+    pub fn release_url() -> String {
+        format!("https://github.com/mglawica/wark/releases/download/v{}/wark",
+            env!("CARGO_PKG_VERSION"))
+    }
+    /// Path in the project to copy running binary to, if configured so
+    pub fn current_path(&self) -> Option<&str> {
+        match *self {
+            InnerWark::Current(Some(ref path)) => Some(path),
+            InnerWark::Current(None) => Some("vagga/wark"),
+            _ => None,
+        }
+    }
+}
+
+impl<'render> Variable<'render> for InnerWark {
+    fn typename(&self) -> &'static str {
+        "InnerWark"
+    }
+    fn attr<'x>(&'x self, attr: &str)
+        -> Result<Var<'x, 'render>, DataError>
+        where 'render: 'x
+    {
+        use self::InnerWark::*;
+        let val = match (self, attr) {
+            (&Release, "url") => InnerWark::release_url(),
+            (&Download(ref dl), "url") => dl.url.clone(),
+            (&Download(WarkDownload { sha256: Some(ref sum), .. }), "sha256")
+            => sum.clone(),
+            (&Copy(ref path), "source") => path.clone(),
+            (&Current(..), "source") => {
+                let path = self.current_path().expect("current binary");
+                format!("/work/{}", path.trim_left_matches("./"))
+            }
+            _ => return Err(DataError::AttrNotFound),
+        };
+        Ok(Var::owned(val))
+    }
+}
+
+impl<'de> Deserialize<'de> for VaggaTemplate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        Option::<String>::deserialize(deserializer).map(VaggaTemplate)
+    }
+}
+
+impl<'render> Variable<'render> for VaggaTemplate {
+    fn typename(&self) -> &'static str {
+        "VaggaTemplate"
+    }
+    fn output(&self) -> Result<Output, DataError> {
+        match self.0 {
+            Some(ref path) => Ok(path.into()),
+            None => Ok(Output::owned("<built-in>")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for VersionCache {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        Option::<String>::deserialize(deserializer).map(VersionCache)
+    }
+}
+
+impl<'render> Variable<'render> for VersionCache {
+    fn typename(&self) -> &'static str {
+        "VersionCache"
+    }
+    fn output(&self) -> Result<Output, DataError> {
+        match self.0 {
+            Some(ref path) => Ok(path.into()),
+            None => Ok(Output::owned("<disabled>")),
+        }
+    }
+}
+
+impl<'render> Variable<'render> for Lock {
+    fn typename(&self) -> &'static str {
+        "Lock"
+    }
+}
+
+impl<'render> Variable<'render> for Target {
+    fn typename(&self) -> &'static str {
+        "Target"
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+use atty::{self, Stream};
+use capturing_glob::Pattern;
+use failure::Error;
+
+use deploy::{Spec, Filter};
+
+
+/// Returns deployments matching `protected` patterns of the config
+fn protected<'x>(spec: &Spec, names: &'x [String]) -> Vec<&'x str> {
+    let patterns = spec.config.protected.iter()
+        .filter_map(|p| Pattern::new(p).ok())
+        .collect::<Vec<_>>();
+    names.iter()
+        .filter(|name| patterns.iter().any(|p| p.matches(name)))
+        .map(|x| &x[..])
+        .collect()
+}
+
+fn print_plan(spec: &Spec, names: &[String], protected: &[&str],
+    partial: &BTreeSet<String>, target: &Option<String>, filter: &Filter)
+{
+    match *target {
+        Some(ref target) => {
+            eprintln!("Deploying version {:?} to target {:?}:",
+                spec.version, target);
+        }
+        None => eprintln!("Deploying version {:?}:", spec.version),
+    }
+    for name in names {
+        let dep = &spec.deployments[name];
+        let mut flags = Vec::new();
+        if protected.contains(&&name[..]) {
+            flags.push("protected");
+        }
+        if partial.contains(name) {
+            flags.push("partial");
+        }
+        if flags.is_empty() {
+            eprintln!("    {}", name);
+        } else {
+            eprintln!("    {} [{}]", name, flags.join(", "));
+        }
+        if !dep.daemons.is_empty() {
+            eprintln!("        daemons: {}", dep.daemons.keys()
+                .map(|x| &x[..]).collect::<Vec<_>>().join(", "));
+        }
+        if !dep.commands.is_empty() {
+            eprintln!("        commands: {}", dep.commands.keys()
+                .map(|x| &x[..]).collect::<Vec<_>>().join(", "));
+        }
+    }
+    eprintln!("Stages: {}", spec.config.script.iter()
+        .filter(|s| filter.stage_selected(s))
+        .map(|s| s.name())
+        .collect::<Vec<_>>().join(", "));
+}
+
+/// Asks to confirm deploy of protected deployments
+///
+/// Shows the plan and asks to type the name of every protected deployment.
+/// With `yes` doesn't ask anything, otherwise fails if stdin is not a
+/// terminal.
+pub fn confirm(spec: &Spec, names: &[String], partial: &BTreeSet<String>,
+    target: &Option<String>, filter: &Filter, yes: bool)
+    -> Result<(), Error>
+{
+    let protected = protected(spec, names);
+    if protected.is_empty() {
+        return Ok(());
+    }
+    print_plan(spec, names, &protected, partial, target, filter);
+    if yes {
+        info!("Deploy of protected {} is confirmed by --yes",
+            protected.join(", "));
+        return Ok(());
+    }
+    if !atty::is(Stream::Stdin) {
+        bail!("deployment {} is protected, use --yes to deploy it \
+            non-interactively", protected.join(", "));
+    }
+    let stdin = io::stdin();
+    for name in protected {
+        eprint!("Type {:?} to deploy it: ", name);
+        io::stderr().flush()?;
+        let mut line = String::new();
+        stdin.lock().read_line(&mut line)?;
+        if line.trim() != name {
+            bail!("deploy of {:?} is not confirmed, aborting", name);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use std::env;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+use serde_json::{to_string, from_str};
+
+use deploy::Context;
+use redact::redact;
+
+
+static HISTORY_FILE: &str = ".vagga/.wark/history.jsonl";
+
+
+/// A deploy attempt, one JSON per line in the history file
+///
+/// Only deploys made from this machine are recorded, dry runs are not.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// Unix timestamp of the end of the deploy
+    pub time: u64,
+    pub user: String,
+    pub target: Option<String>,
+    pub deployment: String,
+    pub version: String,
+    pub containers: BTreeMap<String, String>,
+    /// All processes and all stages were deployed, i.e. this version can
+    /// be rolled back to
+    #[serde(default)]
+    pub complete: bool,
+    pub status: Status,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum Status {
+    Deployed,
+    Failed,
+}
+
+
+impl Entry {
+    /// Entry for the deployment in `ctx`, `error` is `None` on success
+    pub fn new(ctx: &Context, complete: bool, error: Option<String>)
+        -> Entry
+    {
+        let suffix = &ctx.spec.config.container_suffix;
+        Entry {
+            time: SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs()).unwrap_or(0),
+            user: env::var("USER").unwrap_or_else(|_| "unknown".into()),
+            target: ctx.target.clone(),
+            deployment: ctx.deployment.clone(),
+            version: ctx.spec.version.clone(),
+            containers: ctx.spec.deployments[&ctx.deployment].containers()
+                .into_iter()
+                .filter_map(|name| {
+                    let name = format!("{}{}", name, suffix);
+                    let version = ctx.containers.get(&name)?.version.clone();
+                    Some((name, version))
+                })
+                .collect(),
+            complete,
+            status: if error.is_some() {
+                Status::Failed
+            } else {
+                Status::Deployed
+            },
+            error,
+        }
+    }
+}
+
+/// Appends the entry to the history file, secrets are redacted
+pub fn record(entry: &Entry) -> Result<(), Error> {
+    let path = Path::new(HISTORY_FILE);
+    if let Some(dir) = path.parent() {
+        if !dir.is_dir() {
+            create_dir_all(dir)?;
+        }
+    }
+    let mut line = redact(&to_string(entry)?);
+    line.push('\n');
+    OpenOptions::new().create(true).append(true).open(path)
+        .and_then(|mut f| f.write_all(line.as_bytes()))
+        .map_err(|e| format_err!("can't write {:?}: {}", path, e))?;
+    Ok(())
+}
+
+/// Returns the last complete successful deploy of the deployment
+pub fn previous(target: &Option<String>, deployment: &str)
+    -> Result<Option<Entry>, Error>
+{
+    let file = match File::open(HISTORY_FILE) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => bail!("can't read {:?}: {}", HISTORY_FILE, e),
+    };
+    let mut result = None;
+    for (num, line) in BufReader::new(file).lines().enumerate() {
+        let line = line
+            .map_err(|e| format_err!("can't read {:?}: {}", HISTORY_FILE, e))?;
+        let entry: Entry = from_str(&line)
+            .map_err(|e| format_err!("{}:{}: {}", HISTORY_FILE, num+1, e))?;
+        if entry.status == Status::Deployed && entry.complete &&
+            entry.target == *target && entry.deployment == deployment
+        {
+            result = Some(entry);
+        }
+    }
+    Ok(result)
+}
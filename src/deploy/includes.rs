@@ -0,0 +1,284 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+
+use quire::ast::{self, Ast, Tag};
+use quire::{raw_parse, Error, ErrorCollector, Options, Include, Pos};
+use url::Url;
+
+use download::download;
+
+
+/// Loads destination config files resolving `includes:` key
+///
+/// Each file may have an `includes:` key with a single name or a list of
+/// names. Names are either URLs (downloaded via `vagga _capsule download`)
+/// or paths relative to the including file (or joined to its URL if the
+/// including file was downloaded).
+///
+/// Merge order is: includes are applied in the order they are listed, each
+/// next one overriding previous ones, and the including file overrides
+/// everything it includes. Mappings are merged key by key recursively,
+/// any other value (including lists) is replaced as a whole.
+///
+/// All AST nodes keep the name of the file (or URL) they were read from,
+/// so validation errors point to the file where setting came from.
+pub struct Loader {
+    stack: RefCell<Vec<String>>,
+}
+
+
+pub fn is_url(name: &str) -> bool {
+    name.contains("://")
+}
+
+fn resolve(base: &str, name: &str) -> Result<String, String> {
+    if is_url(name) {
+        return Ok(name.to_string());
+    }
+    if is_url(base) {
+        return Url::parse(base)
+            .and_then(|url| url.join(name))
+            .map(|url| url.to_string())
+            .map_err(|e| format!("can't resolve {:?} relative to {:?}: {}",
+                name, base, e));
+    }
+    let path = match Path::new(base).parent() {
+        Some(dir) => dir.join(name),
+        None => Path::new(name).to_path_buf(),
+    };
+    Ok(path.to_str().expect("path is utf-8").to_string())
+}
+
+fn merge(base: Ast, over: Ast) -> Ast {
+    match (base, over) {
+        (Ast::Map(_, _, mut base), Ast::Map(pos, tag, over)) => {
+            for (key, value) in over {
+                let value = match base.remove(&key) {
+                    Some(old) => merge(old, value),
+                    None => value,
+                };
+                base.insert(key, value);
+            }
+            Ast::Map(pos, tag, base)
+        }
+        (_, over) => over,
+    }
+}
+
+impl Loader {
+    pub fn new() -> Loader {
+        Loader {
+            stack: RefCell::new(Vec::new()),
+        }
+    }
+    /// Handler for `!*Include` tag, so it works the same as `includes:`
+    pub fn include(&self, pos: &Pos, incl: &Include,
+        err: &ErrorCollector, opt: &Options)
+        -> Ast
+    {
+        match *incl {
+            Include::File { filename } => {
+                match resolve(&pos.filename, filename) {
+                    Ok(name) => self.load(pos, &name, err, opt),
+                    Err(e) => {
+                        err.add_error(Error::preprocess_error(pos, e));
+                        Ast::void(pos)
+                    }
+                }
+            }
+        }
+    }
+    /// Loads a file with all its includes
+    ///
+    /// The `pos` is a position of the include directive, used for
+    /// reporting errors.
+    pub fn load(&self, pos: &Pos, name: &str,
+        err: &ErrorCollector, opt: &Options)
+        -> Ast
+    {
+        if self.stack.borrow().iter().any(|x| x == name) {
+            err.add_error(Error::preprocess_error(pos,
+                format!("include loop: {} -> {}",
+                    self.stack.borrow().join(" -> "), name)));
+            return Ast::void(pos);
+        }
+        let body = match read(name) {
+            Ok(body) => body,
+            Err(e) => {
+                err.add_error(Error::preprocess_error(pos,
+                    format!("can't read {:?}: {}", name, e)));
+                return Ast::void(pos);
+            }
+        };
+        self.stack.borrow_mut().push(name.to_string());
+        let ast = match raw_parse(Rc::new(name.to_string()), &body,
+                                  |doc| ast::process(opt, doc, err))
+        {
+            Ok(ast) => self.apply_includes(name, ast, err, opt),
+            Err(e) => {
+                err.add_error(e);
+                Ast::void(pos)
+            }
+        };
+        self.stack.borrow_mut().pop();
+        return ast;
+    }
+    fn apply_includes(&self, name: &str, ast: Ast,
+        err: &ErrorCollector, opt: &Options)
+        -> Ast
+    {
+        let (pos, tag, mut map) = match ast {
+            Ast::Map(pos, tag, map) => (pos, tag, map),
+            ast => return ast,
+        };
+        let names = match map.remove("includes") {
+            None | Some(Ast::Null(..)) => return Ast::Map(pos, tag, map),
+            Some(Ast::Scalar(pos, _, _, val)) => vec![(pos, val)],
+            Some(Ast::Seq(_, _, items)) => {
+                items.into_iter().filter_map(|item| match item {
+                    Ast::Scalar(pos, _, _, val) => Some((pos, val)),
+                    item => {
+                        err.add_error(Error::validation_error(&item.pos(),
+                            format!("include must be a file name or url")));
+                        None
+                    }
+                }).collect()
+            }
+            Some(item) => {
+                err.add_error(Error::validation_error(&item.pos(),
+                    format!("includes must be a list of file names or urls")));
+                return Ast::Map(pos, tag, map);
+            }
+        };
+        let mut result = Ast::Map(pos.clone(), Tag::NonSpecific,
+                                  BTreeMap::new());
+        for (ipos, iname) in names {
+            let included = match resolve(name, &iname) {
+                Ok(full) => self.load(&ipos, &full, err, opt),
+                Err(e) => {
+                    err.add_error(Error::preprocess_error(&ipos, e));
+                    continue;
+                }
+            };
+            match included {
+                Ast::Map(..) => result = merge(result, included),
+                Ast::Null(..) => {}
+                other => {
+                    err.add_error(Error::validation_error(&ipos,
+                        format!("included file must contain a mapping, \
+                            got {}", other)));
+                }
+            }
+        }
+        merge(result, Ast::Map(pos, tag, map))
+    }
+}
+
+fn read(name: &str) -> Result<String, String> {
+    let path = if is_url(name) {
+        download(name, true).map_err(|e| e.to_string())?
+    } else {
+        Path::new(name).to_path_buf()
+    };
+    let mut buf = String::with_capacity(1024);
+    File::open(&path)
+        .and_then(|mut f| f.read_to_string(&mut buf))
+        .map_err(|e| e.to_string())?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{File, create_dir_all};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+
+    use quire::{parse_string, Options};
+    use quire::validate::Anything;
+    use serde_json::Value as Json;
+    use serde_json::{to_string, from_str};
+
+    use super::Loader;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir()
+            .join(format!("wark-includes-{}-{}", name, process::id()));
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(dir: &PathBuf, name: &str, data: &str) {
+        File::create(dir.join(name)).unwrap()
+            .write_all(data.as_bytes()).unwrap();
+    }
+
+    fn load(dir: &PathBuf, name: &str) -> Result<Json, String> {
+        let name = dir.join(name).to_str().unwrap().to_string();
+        let loader = Loader::new();
+        let mut options = Options::default();
+        options.allow_include(|pos, incl, err, opt| {
+            loader.include(pos, incl, err, opt)
+        });
+        let root = format!("!*Include {}", to_string(&name).unwrap());
+        parse_string(&name, &root, &Anything, &options)
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn merge_order() {
+        let dir = dir("merge");
+        write(&dir, "base.yaml", "\
+            a: base\n\
+            b: base\n\
+            map: {x: base, y: base}\n\
+            list: [1, 2]\n");
+        write(&dir, "second.yaml", "\
+            b: second\n\
+            map: {y: second}\n");
+        write(&dir, "main.yaml", "\
+            includes: [base.yaml, second.yaml]\n\
+            a: main\n\
+            list: [3]\n");
+        assert_eq!(load(&dir, "main.yaml").unwrap(), from_str::<Json>(r#"{
+            "a": "main",
+            "b": "second",
+            "map": {"x": "base", "y": "second"},
+            "list": ["3"]
+        }"#).unwrap());
+    }
+
+    #[test]
+    fn single_include() {
+        let dir = dir("single");
+        write(&dir, "base.yaml", "a: base\nb: base\n");
+        write(&dir, "main.yaml", "includes: base.yaml\nb: main\n");
+        assert_eq!(load(&dir, "main.yaml").unwrap(),
+                   from_str::<Json>(r#"{"a": "base", "b": "main"}"#).unwrap());
+    }
+
+    #[test]
+    fn include_loop() {
+        let dir = dir("loop");
+        write(&dir, "a.yaml", "includes: b.yaml\na: 1\n");
+        write(&dir, "b.yaml", "includes: a.yaml\nb: 1\n");
+        let err = load(&dir, "a.yaml").unwrap_err();
+        assert!(err.contains("include loop"), "{}", err);
+    }
+
+    #[test]
+    fn include_twice() {
+        // not a loop, the same file may be included by different files
+        let dir = dir("twice");
+        write(&dir, "common.yaml", "c: common\n");
+        write(&dir, "a.yaml", "includes: common.yaml\na: a\n");
+        write(&dir, "main.yaml", "includes: [common.yaml, a.yaml]\n");
+        assert_eq!(load(&dir, "main.yaml").unwrap(),
+                   from_str::<Json>(r#"{"a": "a", "c": "common"}"#).unwrap());
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::env;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions, DirBuilder, Permissions, set_permissions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+
+use failure::Error;
+use futures::{Future, Async};
+use futures::future::{FutureResult, ok};
+use futures::sync::oneshot;
+use libc;
+use ns_env_config;
+use serde_json::{to_vec, from_slice, Value as Json};
+use sha1::Sha1;
+use tk_easyloop::{self, handle};
+use tk_http::Version;
+use tk_http::client::{RecvMode, Head, Error as HError, Encoder, EncoderDone};
+use tk_http::client::Codec;
+use trimmer::{Context as Vars};
+use url::Url;
+
+use deploy::Spec;
+use http;
+use templates::{Value, values};
+
+
+/// Lock of a single deployment held while it's being deployed
+///
+/// Local lock is a `flock` on a file in `/tmp/wark-locks`, so it's shared
+/// by all users of the machine and released by the kernel if wark crashes.
+/// File name contains a hash of the destination config path (or url), so
+/// deployments of different projects don't block each other.
+///
+/// Cluster-wide lock is taken if `lock.url` is configured. Protocol is:
+///
+/// * `POST <url>` with `{"owner": .., "deployment": .., "timeout": ..}`
+///   acquires a lock, any 2xx status means success, `409 Conflict` or
+///   `423 Locked` means lock is held by somebody else, response body is
+///   either a JSON with `owner` field or a text describing the holder
+/// * `DELETE <url>` with `{"owner": ..}` releases the lock, with
+///   `"force": true` releases it even if held by somebody else
+///
+/// Server should expire lock after `timeout` seconds, in case wark is
+/// killed before releasing it.
+pub struct DeployLock {
+    deployment: String,
+    owner: String,
+    file: Option<File>,
+    cluster: Option<Url>,
+}
+
+#[derive(Debug, Serialize)]
+struct LockRequest<'a> {
+    owner: &'a str,
+    deployment: &'a str,
+    timeout: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct UnlockRequest<'a> {
+    owner: &'a str,
+    force: bool,
+}
+
+struct Request {
+    method: &'static str,
+    url: Url,
+    body: Vec<u8>,
+    status: u16,
+    tx: Option<oneshot::Sender<(u16, Vec<u8>)>>,
+}
+
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let rc = unsafe {
+        libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len())
+    };
+    if rc != 0 {
+        return String::from("unknown");
+    }
+    buf[buf.len()-1] = 0;
+    unsafe { CStr::from_ptr(buf.as_ptr() as *const libc::c_char) }
+        .to_string_lossy().into_owned()
+}
+
+/// Describes current process for lock holder messages
+fn owner(spec: &Spec) -> String {
+    let user = env::var("USER").unwrap_or_else(|_| String::from("unknown"));
+    format!("{}@{} (pid {}, version {:?})",
+        user, hostname(), unsafe { libc::getpid() }, spec.version)
+}
+
+/// Renders url of the cluster-wide lock, `None` if not configured
+pub fn url(spec: &Spec, deployment: &str, target: &Option<String>,
+    vars: &HashMap<String, String>)
+    -> Result<Option<Url>, Error>
+{
+    let pattern = match spec.config.lock.url {
+        Some(ref pattern) => pattern,
+        None => return Ok(None),
+    };
+    let deployment = Value(deployment.to_string());
+    let target = target.as_ref().map(|x| &x[..]);
+    let vars = values(vars);
+    let mut context = Vars::new();
+    context.set("deployment", &deployment);
+    context.set("target", &target);
+    context.set("vars", &vars);
+    let text = pattern.render_key("lock.url", &context)?;
+    let url = Url::parse(&text)
+        .map_err(|e| format_err!("invalid lock.url {:?}: {}", text, e))?;
+    if url.scheme() != "http" {
+        bail!("only http is supported for lock.url, got {:?}", text);
+    }
+    Ok(Some(url))
+}
+
+/// Short hash identifying the destination config
+fn destination_hash(spec: &Spec) -> String {
+    let mut hash = Sha1::new();
+    hash.update(spec.config.source.as_bytes());
+    hash.digest().to_string()[..12].to_string()
+}
+
+fn lock_file(name: &str, destination: &str, owner: &str, force: bool)
+    -> Result<Option<File>, Error>
+{
+    let dir = env::temp_dir().join("wark-locks");
+    match DirBuilder::new().mode(0o1777).create(&dir) {
+        // mode is limited by umask, but we want other users to lock too
+        Ok(()) => set_permissions(&dir, Permissions::from_mode(0o1777))?,
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => bail!("can't create {:?}: {}", dir, e),
+    }
+    let path = dir.join(format!("{}.{}.lock", name, destination));
+    // flock works on read-only files, so a file created by another user
+    // can be locked too, but we can't record the owner in it
+    let mut file = OpenOptions::new()
+        .read(true).write(true).create(true).open(&path)
+        .or_else(|_| File::open(&path))
+        .map_err(|e| format_err!("can't open {:?}: {}", path, e))?;
+    let rc = unsafe {
+        libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB)
+    };
+    if rc != 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::WouldBlock {
+            bail!("can't lock {:?}: {}", path, e);
+        }
+        let mut holder = String::new();
+        file.read_to_string(&mut holder).ok();
+        let holder = if holder.trim().is_empty() {
+            "unknown process"
+        } else {
+            holder.trim()
+        };
+        if force {
+            warn!("Ignoring local lock of {:?} held by {}", name, holder);
+            return Ok(None);
+        }
+        bail!("deployment {:?} is being deployed from this machine by {}",
+            name, holder);
+    }
+    if file.set_len(0).and_then(|()| file.write_all(owner.as_bytes()))
+        .is_err()
+    {
+        debug!("Can't record lock owner in {:?}", path);
+    }
+    Ok(Some(file))
+}
+
+fn lock_request(url: &Url, owner: &str, deployment: &str, timeout: u64)
+    -> Result<(), Error>
+{
+    let body = to_vec(&LockRequest { owner, deployment, timeout })
+        .expect("can serialize lock request");
+    let (status, data) = request("POST", url, body)?;
+    match status {
+        s if s >= 200 && s < 300 => Ok(()),
+        409 | 423 => {
+            let json = from_slice::<Json>(&data).ok();
+            let holder = match json.as_ref().and_then(|j| j.get("owner")) {
+                Some(&Json::String(ref owner)) => owner.clone(),
+                Some(owner) => owner.to_string(),
+                None => String::from_utf8_lossy(&data).trim().to_string(),
+            };
+            bail!("deployment {:?} is locked by {}", deployment, holder)
+        }
+        _ => bail!("lock request to {} failed with status {}: {}",
+            url, status, String::from_utf8_lossy(&data).trim()),
+    }
+}
+
+fn unlock_request(url: &Url, owner: &str, force: bool) -> Result<(), Error> {
+    let body = to_vec(&UnlockRequest { owner, force })
+        .expect("can serialize unlock request");
+    let (status, data) = request("DELETE", url, body)?;
+    match status {
+        s if s >= 200 && s < 300 || s == 404 => Ok(()),
+        _ => bail!("unlock request to {} failed with status {}: {}",
+            url, status, String::from_utf8_lossy(&data).trim()),
+    }
+}
+
+/// Locks deployment, both locally and cluster-wide if configured
+///
+/// With `force` the cluster lock is released before acquiring it, and
+/// local lock held by another process is ignored.
+pub fn acquire(spec: &Spec, deployment: &str, target: &Option<String>,
+    vars: &HashMap<String, String>, force: bool)
+    -> Result<DeployLock, Error>
+{
+    let owner = owner(spec);
+    let name = match *target {
+        Some(ref target) => format!("{}.{}", target, deployment),
+        None => deployment.to_string(),
+    };
+    let file = lock_file(&name, &destination_hash(spec), &owner, force)?;
+    let cluster = url(spec, deployment, target, vars)?;
+    if let Some(ref url) = cluster {
+        if force {
+            warn!("Forcing unlock of {:?}", deployment);
+            unlock_request(url, &owner, true)?;
+        }
+        lock_request(url, &owner, deployment, spec.config.lock.timeout)?;
+        info!("Locked {:?} at {}", deployment, url);
+    }
+    Ok(DeployLock {
+        deployment: deployment.to_string(),
+        owner, file, cluster,
+    })
+}
+
+impl DeployLock {
+    /// Releases the lock, local one is also released by kernel on exit
+    pub fn release(self) {
+        if let Some(ref file) = self.file {
+            file.set_len(0).ok();
+        }
+        if let Some(ref url) = self.cluster {
+            match unlock_request(url, &self.owner, false) {
+                Ok(()) => debug!("Unlocked {:?}", self.deployment),
+                Err(e) => {
+                    warn!("Can't unlock {:?}: {}. \
+                        Lock will expire after timeout.", self.deployment, e);
+                }
+            }
+        }
+    }
+}
+
+fn request(method: &'static str, url: &Url, body: Vec<u8>)
+    -> Result<(u16, Vec<u8>), Error>
+{
+    let host = url.host_str()
+        .ok_or_else(|| format_err!("no host in url {}", url))?
+        .to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let (tx, rx) = oneshot::channel();
+    let codec = Request {
+        method,
+        url: url.clone(),
+        body,
+        status: 0,
+        tx: Some(tx),
+    };
+    tk_easyloop::run(move || {
+        let ns = ns_env_config::init(&handle())
+            .expect("name system init");
+        http::resolve(&ns, &host, port)
+        .and_then(move |addr| http::request(addr, codec, rx))
+    })
+}
+
+impl<S> Codec<S> for Request {
+    type Future = FutureResult<EncoderDone<S>, HError>;
+    fn start_write(&mut self, mut e: Encoder<S>) -> Self::Future {
+        let path = match self.url.query() {
+            Some(query) => format!("{}?{}", self.url.path(), query),
+            None => self.url.path().to_string(),
+        };
+        e.request_line(self.method, &path, Version::Http11);
+        e.add_header("Host", self.url.host_str().unwrap_or("")).unwrap();
+        e.add_header("Content-Type", "application/json").unwrap();
+        e.add_header("User-Agent",
+            concat!("wark/", env!("CARGO_PKG_VERSION"))).unwrap();
+        e.add_length(self.body.len() as u64).unwrap();
+        e.done_headers().unwrap();
+        e.write_body(&self.body);
+        ok(e.done())
+    }
+    fn headers_received(&mut self, headers: &Head) -> Result<RecvMode, HError> {
+        self.status = headers.raw_status().0;
+        Ok(RecvMode::buffered(65536))
+    }
+    fn data_received(&mut self, data: &[u8], end: bool)
+        -> Result<Async<usize>, HError>
+    {
+        assert!(end);
+        self.tx.take().expect("once").send((self.status, data.to_vec())).ok();
+        Ok(Async::Ready(data.len()))
+    }
+}
@@ -1,32 +1,152 @@
 use std::str::from_utf8;
-use std::process::{Command, Stdio, exit};
+use std::process::{Command, Stdio};
 use std::collections::{BTreeSet, BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::time::Instant;
 
 
 pub mod config;
 pub mod spec;
+pub mod vars;
+mod confirm;
+mod history;
+mod includes;
+mod lock;
+mod state;
+mod timing;
 mod tools;
+mod version_cache;
 
-use local::check_config;
+use capturing_glob::Pattern;
+use failure::Error;
+use serde_json::to_string_pretty;
+use trimmer::{Context as Vars};
+
+use local::{check_config, check_binary, check_containers};
+use local::vagga::{self, version_hash};
 pub use self::config::{Config, Stage};
-pub use self::spec::{Spec, parse_spec_or_exit};
+pub use self::spec::{Spec, parse_spec, parse_spec_or_exit};
+pub use self::timing::Timings;
+
+use exit::{ExitCode, ErrorKind};
+use redact::redact;
+use templates::{Value, values};
+use self::state::DeployState;
+use self::timing::Seconds;
+use self::version_cache::VersionCache;
+
+
+static VAGGA_ROOTS: &str = ".vagga/.roots";
 
-use exit::ExitCode;
 
+/// Selects processes of the deployment and stages of the script to run,
+/// all by default
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub only: Vec<String>,
+    pub exclude: Vec<String>,
+    pub stages: Vec<String>,
+    pub skip_stages: Vec<String>,
+}
+
+/// Command-line switches changing how deployment is done
+#[derive(Debug, Default)]
+pub struct Flags {
+    pub dry_run: bool,
+    pub refresh: bool,
+    pub force_unlock: bool,
+    pub resume: bool,
+    pub yes: bool,
+    pub json: bool,
+}
 
 #[derive(Debug)]
 struct Container {
     version: String,
 }
 
+/// Failed stage of the script, available to `on_failure` stages
+#[derive(Debug, Clone, Variable)]
+struct Failure {
+    stage: String,
+    error: String,
+    kind: ErrorKind,
+}
+
 #[derive(Debug)]
-struct Context {
-    spec: Spec,
+struct Context<'a> {
+    spec: &'a Spec,
     dry_run: bool,
     deployment: String,
+    target: Option<String>,
+    partial: bool,
     containers: BTreeMap<String, Container>,
+    failure: Option<Failure>,
+    /// Last version of the deployment completely deployed from here
+    previous: Option<history::Entry>,
+}
+
+/// Result of a single deployment in the JSON report
+#[derive(Debug, Serialize)]
+struct Outcome {
+    status: &'static str,
+    #[serde(skip_serializing_if="Option::is_none")]
+    failed_stage: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    error_kind: Option<ErrorKind>,
 }
 
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    version: Option<&'a str>,
+    target: Option<&'a str>,
+    dry_run: bool,
+    /// Error which stopped the whole run rather than a single deployment
+    #[serde(skip_serializing_if="Option::is_none")]
+    error: Option<String>,
+    deployments: BTreeMap<&'a str, Outcome>,
+    timings: &'a Timings,
+}
+
+/// What is known about the run for the final report
+struct Run<'a> {
+    version: Option<&'a str>,
+    target: &'a Option<String>,
+    flags: &'a Flags,
+    timings: &'a Timings,
+}
+
+
+/// Appends the result of the deploy to the local history, unless dry run
+///
+/// Deploy is `complete` if all stages are run for all the processes.
+fn record(ctx: &Context, complete: bool, failure: Option<&Failure>) {
+    if ctx.dry_run {
+        return;
+    }
+    let error = failure.map(|f| format!("stage {:?}: {}", f.stage, f.error));
+    let entry = history::Entry::new(ctx, complete && !ctx.partial, error);
+    if let Err(e) = history::record(&entry) {
+        warn!("Can't record deploy history: {}", e);
+    }
+}
+
+impl Filter {
+    fn stage_selected(&self, stage: &Stage) -> bool {
+        let name = stage.name();
+        (self.stages.is_empty() || self.stages.iter().any(|x| x == name)) &&
+            !self.skip_stages.iter().any(|x| x == name)
+    }
+}
+
+/// Checks that container root of the version exists locally, so it can
+/// be uploaded without building
+fn root_exists(version: &str) -> bool {
+    Path::new(VAGGA_ROOTS).join(version).join("root").is_dir()
+}
 
 fn check_ver(s: &str) -> bool {
     s.len() > 0 && s.chars().all(|x| {
@@ -34,66 +154,444 @@ fn check_ver(s: &str) -> bool {
     })
 }
 
-pub fn main(config: Config, deployment: String, dry_run: bool,
-            vars: HashMap<String, String>)
+/// Resolves names, globs (like `eu-*`) to the list of deployments
+///
+/// Order is preserved as specified, names matched by a glob are sorted.
+fn select_deployments(spec: &Spec, patterns: &[String])
+    -> Result<Vec<String>, Error>
+{
+    let mut result = Vec::new();
+    for pattern in patterns {
+        let matched = if spec.deployments.contains_key(pattern) {
+            vec![pattern.clone()]
+        } else {
+            let glob = Pattern::new(pattern)
+                .map_err(|e| format_err!("bad pattern {:?}: {}", pattern, e))?;
+            spec.deployments.keys()
+                .filter(|name| glob.matches(name))
+                .cloned().collect::<Vec<_>>()
+        };
+        if matched.is_empty() {
+            bail!("No deployment {:?} found, available: {}", pattern,
+                spec.deployments.keys().map(|x| &x[..])
+                .collect::<Vec<_>>().join(", "));
+        }
+        for name in matched {
+            if !result.contains(&name) {
+                result.push(name);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Evaluates `when` condition of the stage
+///
+/// Condition may refer to `deployment`, `target`, `vars`, `failure` (in
+/// `on_failure` stages), `previous_version` and constants `true` and
+/// `false`.
+fn stage_enabled(ctx: &Context, stage: &Stage,
+    vars: &HashMap<String, String>)
+    -> Result<bool, Error>
+{
+    let condition = match stage.when() {
+        Some(condition) => condition,
+        None => return Ok(true),
+    };
+    let deployment = Value(ctx.deployment.clone());
+    let target = ctx.target.as_ref().map(|x| &x[..]);
+    let previous = Value(ctx.previous.as_ref()
+        .map(|x| x.version.clone()).unwrap_or_default());
+    let vars = values(vars);
+    let (yes, no) = (true, false);
+    let mut context = Vars::new();
+    context.set("deployment", &deployment);
+    context.set("target", &target);
+    context.set("previous_version", &previous);
+    context.set("vars", &vars);
+    context.set("true", &yes);
+    context.set("false", &no);
+    if let Some(ref failure) = ctx.failure {
+        context.set("failure", failure);
+    }
+    condition.check_key(&format!("{}.when", stage.name()), &context)
+}
+
+/// Runs script for the deployment in `ctx`
+///
+/// Stages that don't depend on deployment (ciruela upload) run only once
+/// for all deployments, `done` keeps indexes of such stages (including
+/// ones completed by the resumed deploy). Stages completed by the resumed
+/// deploy are skipped, as well as ones not selected by `filter`. Stages
+/// with false condition are skipped too, but they are considered completed.
+/// Duration of each stage that is run is recorded in `timings`.
+fn run_script(ctx: &Context, vars: &HashMap<String, String>,
+    filter: &Filter, done: &mut BTreeSet<usize>, state: &mut DeployState,
+    timings: &mut Timings)
+    -> Result<(), Failure>
+{
+    for (idx, item) in ctx.spec.config.script.iter().enumerate() {
+        let failed = |e: Error| Failure {
+            stage: item.name().to_string(),
+            error: redact(&e.to_string()),
+            kind: ErrorKind::of(&e).unwrap_or_else(|| default_kind(item)),
+        };
+        if state.is_completed(&ctx.deployment, idx) {
+            info!("Stage {:?} is completed by previous deploy, skipping",
+                item.name());
+            continue;
+        }
+        if !filter.stage_selected(item) {
+            info!("Stage {:?} is not selected, skipping", item.name());
+            continue;
+        }
+        if stage_enabled(ctx, item, vars).map_err(&failed)? {
+            info!("Running stage {:?}", item.name());
+        } else {
+            info!("Stage {:?} is skipped, condition is false: {}",
+                item.name(), item.when().map(|x| x.to_string())
+                .unwrap_or_default());
+            if let Err(e) = state.complete(&ctx.deployment, idx) {
+                warn!("Can't save deploy state: {}", e);
+            }
+            continue;
+        }
+        let once = matches!(*item, Stage::Ciruela(..));
+        if !once || done.insert(idx) {
+            let start = Instant::now();
+            let result = execute(ctx, item, vars);
+            let dur = timings.add(format!("{}: stage {}",
+                ctx.deployment, item.name()), start);
+            result.map_err(&failed)?;
+            info!("Stage {:?} done in {}", item.name(), Seconds(dur));
+        }
+        if let Err(e) = state.complete(&ctx.deployment, idx) {
+            warn!("Can't save deploy state: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Runs `on_failure` stages, their errors are only logged
+fn run_on_failure(ctx: &Context, vars: &HashMap<String, String>,
+    timings: &mut Timings)
+{
+    for item in &ctx.spec.config.on_failure {
+        match stage_enabled(ctx, item, vars) {
+            Ok(true) => info!("Running on-failure stage {:?}", item.name()),
+            Ok(false) => {
+                info!("On-failure stage {:?} is skipped, \
+                    condition is false: {}", item.name(),
+                    item.when().map(|x| x.to_string()).unwrap_or_default());
+                continue;
+            }
+            Err(e) => {
+                error!("On-failure stage {:?}: {}", item.name(), e);
+                continue;
+            }
+        }
+        let start = Instant::now();
+        let result = execute(ctx, item, vars);
+        timings.add(format!("{}: on-failure {}", ctx.deployment, item.name()),
+            start);
+        if let Err(e) = result {
+            error!("On-failure stage {:?} failed: {}", item.name(), e);
+        }
+    }
+}
+
+/// Kind of errors of the stage which don't have their own kind
+fn default_kind(stage: &Stage) -> ErrorKind {
+    match *stage {
+        Stage::Ciruela(..) => ErrorKind::Upload,
+        Stage::VerwalterKokkupanek(..) => ErrorKind::Verwalter,
+        Stage::Command(..) => ErrorKind::Command,
+    }
+}
+
+fn execute(ctx: &Context, stage: &Stage, vars: &HashMap<String, String>)
+    -> Result<(), Error>
+{
+    match *stage {
+        Stage::Ciruela(ref settings) => {
+            tools::ciruela::execute(ctx, settings, vars)
+        }
+        Stage::VerwalterKokkupanek(ref settings) => {
+            tools::kokkupanek::execute(ctx, settings, vars)
+        }
+        Stage::Command(ref settings) => {
+            tools::command::execute(ctx, settings, vars)
+        }
+    }
+}
+
+/// Renders all the templates of the stage, doesn't run anything
+fn check(ctx: &Context, stage: &Stage, vars: &HashMap<String, String>)
+    -> Result<(), Error>
+{
+    match *stage {
+        Stage::Ciruela(ref settings) => {
+            tools::ciruela::check(ctx, settings, vars)
+        }
+        Stage::VerwalterKokkupanek(ref settings) => {
+            tools::kokkupanek::check(ctx, settings, vars)
+        }
+        Stage::Command(ref settings) => {
+            tools::command::check(ctx, settings, vars)
+        }
+    }
+}
+
+/// Runs the deploy, `timings` should already contain config download
+pub fn main(mut config: Config, deployments: Vec<String>,
+            mut vars: HashMap<String, String>, target: Option<String>,
+            filter: Filter, flags: Flags, mut timings: Timings)
     -> !
 {
-    let spec = parse_spec_or_exit(config);
     let mut code = ExitCode::new();
+    if let Err(e) = config.select_target(&target, &mut vars) {
+        let run = Run::new(None, &target, &flags, &timings);
+        abort(run, ErrorKind::Validation, e, &[], Vec::new(), code);
+    }
+    for name in filter.stages.iter().chain(&filter.skip_stages) {
+        if !config.script.iter().any(|s| s.name() == name) {
+            let run = Run::new(None, &target, &flags, &timings);
+            abort(run, ErrorKind::Validation, format_args!(
+                "No stage {:?} in script, available: {}", name,
+                config.script.iter().map(|s| s.name())
+                .collect::<Vec<_>>().join(", ")),
+                &[], Vec::new(), code);
+        }
+    }
+    let vars = match vars::validate(&config.variables, vars) {
+        Ok(vars) => vars,
+        Err(errors) => {
+            let run = Run::new(None, &target, &flags, &timings);
+            abort(run, ErrorKind::Validation, errors.join("; "),
+                &[], Vec::new(), code);
+        }
+    };
+    let start = Instant::now();
+    let mut spec = parse_spec(config, &mut code);
+    match vagga::containers(Path::new(vagga::VAGGA_CONFIG)) {
+        Ok(defined) => check_containers(&spec, &defined, &mut code),
+        Err(e) => code.error(e),
+    }
+    timings.add("spec", start);
+    if !code.is_ok() {
+        let run = Run::new(Some(&spec.version), &target, &flags, &timings);
+        abort(run, ErrorKind::Config, "deployment spec has errors",
+            &[], Vec::new(), code);
+    }
     let mut failed = BTreeSet::new();
+
+    let mut names = match select_deployments(&spec, &deployments) {
+        Ok(names) => names,
+        Err(e) => {
+            let run = Run::new(Some(&spec.version), &target, &flags, &timings);
+            abort(run, ErrorKind::Validation, e, &[], Vec::new(), code);
+        }
+    };
+
+    for name in &names {
+        match check_config(&spec, name) {
+            Ok(true) => {}
+            Ok(false) => {
+                error!("Vagga config for {:?} is not up to date.", name);
+                info!("Please run: vagga deploy update");
+                code.report(ErrorKind::Validation);
+            }
+            Err(e) => {
+                let run = Run::new(Some(&spec.version), &target,
+                                   &flags, &timings);
+                abort(run, ErrorKind::Config,
+                    format_args!("Error checking vagga config: {}", e),
+                    &names, Vec::new(), code);
+            }
+        }
+    }
+    if let Some(path) = spec.config.inner_wark.current_path() {
+        match check_binary(&spec) {
+            Ok(true) => {}
+            Ok(false) => {
+                error!("Wark binary {:?} is not up to date \
+                    with the running one.", path);
+                info!("Please run: vagga deploy update");
+                code.report(ErrorKind::Validation);
+            }
+            Err(e) => {
+                let run = Run::new(Some(&spec.version), &target,
+                                   &flags, &timings);
+                abort(run, ErrorKind::Config,
+                    format_args!("Error checking wark binary: {}", e),
+                    &names, Vec::new(), code);
+            }
+        }
+    }
+
+    // filtered after checking config, as vagga config has all processes
+    let all_processes = names.iter()
+        .flat_map(|name| spec.deployments[name].processes())
+        .collect::<BTreeSet<_>>();
+    for name in filter.only.iter().chain(&filter.exclude) {
+        if !all_processes.contains(name) {
+            let run = Run::new(Some(&spec.version), &target, &flags, &timings);
+            abort(run, ErrorKind::Validation, format_args!(
+                "No process {:?} in selected deployments, available: {}",
+                name, all_processes.iter().map(|x| &x[..])
+                .collect::<Vec<_>>().join(", ")),
+                &names, Vec::new(), code);
+        }
+    }
+    let mut partial = BTreeSet::new();
+    for name in &names {
+        let dep = spec.deployments.get_mut(name).expect("deployment exists");
+        if dep.filter(&filter.only, &filter.exclude) {
+            if dep.processes().is_empty() {
+                info!("Skipping {:?}: no processes selected", name);
+            } else {
+                info!("Partial deploy of {:?}, processes: {}",
+                    name, dep.processes().into_iter()
+                    .collect::<Vec<_>>().join(", "));
+            }
+            partial.insert(name.clone());
+        }
+    }
+    names.retain(|name| !spec.deployments[name].processes().is_empty());
+    if names.is_empty() {
+        let run = Run::new(Some(&spec.version), &target, &flags, &timings);
+        abort(run, ErrorKind::Validation,
+            "No processes left to deploy after filtering",
+            &names, Vec::new(), code);
+    }
+    if !flags.dry_run {
+        let start = Instant::now();
+        let res = confirm::confirm(&spec, &names, &partial, &target,
+                                   &filter, flags.yes);
+        timings.add("confirm", start);
+        if let Err(e) = res {
+            let run = Run::new(Some(&spec.version), &target, &flags, &timings);
+            abort(run, ErrorKind::Validation, e, &names, Vec::new(), code);
+        }
+    }
+
     let mut context = Context {
-        spec, dry_run, deployment,
+        spec: &spec, dry_run: flags.dry_run, target,
+        deployment: String::new(),
+        partial: false,
         containers: BTreeMap::new(),
+        failure: None,
+        previous: None,
     };
-
-    let deployment = match context.spec.deployments.get(&context.deployment) {
-        Some(d) => d,
+    let containers = names.iter()
+        .flat_map(|name| context.spec.deployments[name].containers())
+        .collect::<BTreeSet<_>>();
+    let mut cache = match context.spec.config.version_cache.0 {
+        Some(ref path) => match VersionCache::load(path, flags.refresh) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                let run = Run::new(Some(&spec.version), &context.target,
+                                   &flags, &timings);
+                abort(run, ErrorKind::Config, e, &names, Vec::new(), code);
+            }
+        },
         None => {
-            error!("No deployment {:?} found", context.deployment);
-            exit(1);
+            if flags.refresh {
+                warn!("Option --refresh is ignored, \
+                    version_cache is not configured");
+            }
+            None
         }
     };
 
-    match check_config(&context.spec) {
-        Ok(true) => {}
-        Ok(false) => {
-            error!("Config {:?} is not up to date.",
-                context.spec.config.vagga_config);
-            info!("Please run: vagga deploy update");
-            code.report_error();
-        }
-        Err(e) => {
-            error!("Error checking {:?}: {}",
-                context.spec.config.vagga_config, e);
-            code.exit();
+    let mut locks = Vec::new();
+    if !flags.dry_run {
+        let start = Instant::now();
+        for name in &names {
+            match lock::acquire(context.spec, name, &context.target, &vars,
+                                flags.force_unlock)
+            {
+                Ok(lock) => locks.push(lock),
+                Err(e) => {
+                    timings.add("lock", start);
+                    info!("Use --force-unlock if you're sure \
+                        nobody is deploying it now");
+                    let run = Run::new(Some(&spec.version), &context.target,
+                                       &flags, &timings);
+                    abort(run, ErrorKind::Lock,
+                        format_args!("Can't lock deployment: {}", e),
+                        &names, locks, code);
+                }
+            }
         }
+        timings.add("lock", start);
     }
-    let containers = deployment.commands.values().map(|x| &x.container)
-        .chain(deployment.daemons.values().map(|x| &x.container));
+
+    let (mut hits, mut misses) = (0, 0);
     for container in containers {
         let dep_container = format!("{}{}",
             container, context.spec.config.container_suffix);
         if context.containers.contains_key(&dep_container) {
             continue;
         }
+        let hash = if cache.is_some() {
+            match version_hash(&dep_container) {
+                Ok(hash) => Some(hash),
+                Err(e) => {
+                    warn!("Can't get version hash of container {:?}, \
+                        building it: {}", dep_container, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let cached = match (cache.as_ref(), hash.as_ref()) {
+            (Some(cache), Some(hash)) => {
+                cache.get(&dep_container, hash).map(String::from)
+            }
+            _ => None,
+        };
+        let cached = match cached {
+            Some(ref version) if !root_exists(version) => {
+                info!("Container {:?} of cached version {:?} is not \
+                    built on this machine, building", dep_container, version);
+                None
+            }
+            cached => cached,
+        };
+        if let Some(version) = cached {
+            info!("Container {:?} is unchanged, cached version {:?}",
+                dep_container, version);
+            hits += 1;
+            context.containers.insert(dep_container.clone(), Container {
+                version: version,
+            });
+            continue;
+        }
+        if cache.is_some() {
+            misses += 1;
+        }
+        let start = Instant::now();
         let output = Command::new("vagga")
             .arg("_capsule").arg("build").arg(&dep_container)
             .arg("--print-version")
             .stderr(Stdio::inherit())
             .stdout(Stdio::piped())
             .output();
+        timings.add(format!("build {}", dep_container), start);
         let ver_bytes = match output.as_ref().map(|x| (x.status, &x.stdout)) {
             Ok((s, ver_bytes)) if s.success() => ver_bytes,
             Ok((s, _)) => {
                 error!("Container {:?} failed to build with status: {}",
                     dep_container, s);
-                code.report_error();
+                code.report(ErrorKind::Build);
                 failed.insert(dep_container.clone());
                 continue;
             }
             Err(e) => {
                 error!("Can't build container {:?}: {}", dep_container, e);
-                code.report_error();
+                code.report(ErrorKind::Build);
                 failed.insert(dep_container.clone());
                 continue;
             }
@@ -103,54 +601,308 @@ pub fn main(config: Config, deployment: String, dry_run: bool,
             _ => {
                 error!("Invalid version returned for container {:?}: {:?}",
                     dep_container, String::from_utf8_lossy(&ver_bytes));
-                code.report_error();
+                code.report(ErrorKind::Build);
                 failed.insert(dep_container.clone());
                 continue;
             }
         };
+        if let (Some(cache), Some(hash)) = (cache.as_mut(), hash) {
+            cache.insert(&dep_container, hash, version.clone());
+        }
         context.containers.insert(dep_container.clone(), Container {
             version: version,
         });
     }
+    if let Some(ref cache) = cache {
+        info!("Version cache: {} hits, {} misses", hits, misses);
+        if let Err(e) = cache.save() {
+            warn!("Can't save version cache: {}", e);
+        }
+    }
 
     info!("Built containers {:?}",
         context.containers.values().map(|x| &x.version).collect::<Vec<_>>());
     if failed.len() > 0 {
         error!("Failed containers {:?}", failed);
     }
-    code.exit_if_failed();
-
-    for item in &context.spec.config.script {
-        match *item {
-            Stage::Ciruela(ref settings) => {
-                match tools::ciruela::execute(&context, settings, &vars) {
-                    Ok(()) => {}
-                    Err(e) => {
-                        error!("Version {:?} failed to deploy: {}",
-                            context.spec.version, e);
-                        exit(1);
-                    }
-                }
+    if !code.is_ok() {
+        let outcomes = names.iter()
+            .map(|name| (&name[..], Outcome::skipped())).collect();
+        let run = Run::new(Some(&spec.version), &context.target,
+                           &flags, &timings);
+        finish(run, outcomes, None, locks, code);
+    }
+
+    let target = match context.target {
+        Some(ref name) => format!(" to target {:?}", name),
+        None => String::new(),
+    };
+    let mut state = DeployState::new(&context, &names);
+    let res = if flags.resume {
+        state.resume().map(|resumed| if !resumed {
+            warn!("No failed deploy to resume, deploying from the start");
+        })
+    } else {
+        state.reset()
+    };
+    if let Err(e) = res {
+        let run = Run::new(Some(&spec.version), &context.target,
+                           &flags, &timings);
+        abort(run, ErrorKind::Validation,
+            format_args!("Can't resume deploy: {}", e),
+            &names, locks, code);
+    }
+
+    // only versions deployed with all stages can be rolled back to
+    let complete = filter.stages.is_empty() && filter.skip_stages.is_empty();
+
+    // stages running once, which are completed for any deployment
+    let mut done = state.completed_stages().into_iter()
+        .filter(|&idx| matches!(context.spec.config.script[idx],
+                                Stage::Ciruela(..)))
+        .collect::<BTreeSet<_>>();
+    let mut results = Vec::new();
+    for name in &names {
+        context.deployment = name.clone();
+        context.partial = partial.contains(name);
+        context.previous = history::previous(&context.target, name)
+            .unwrap_or_else(|e| {
+                warn!("Can't read deploy history, \
+                    previous version is unknown: {}", e);
+                None
+            });
+        let result = run_script(&context, &vars, &filter,
+                                &mut done, &mut state, &mut timings);
+        record(&context, complete, result.as_ref().err());
+        if let Err(ref failure) = result {
+            error!("Stage {:?} of {:?} failed: {}",
+                failure.stage, name, failure.error);
+            context.failure = Some(failure.clone());
+            run_on_failure(&context, &vars, &mut timings);
+            context.failure = None;
+        }
+        let ok = result.is_ok();
+        results.push((name, result));
+        if !ok {
+            break;
+        }
+    }
+    // deploy is partial, rather than not started, if any stage is done,
+    // either in this run or in the resumed one
+    let started = !flags.dry_run && (
+        results.iter().any(|&(_, ref result)| result.is_ok()) ||
+        !state.completed_stages().is_empty());
+    if results.len() == names.len() &&
+        results.iter().all(|&(_, ref result)| result.is_ok())
+    {
+        if let Err(e) = state.finish() {
+            warn!("Can't clear deploy state: {}", e);
+        }
+    }
+    let mut outcomes = BTreeMap::new();
+    for name in &names {
+        let outcome = match results.iter().find(|&&(n, _)| n == name) {
+            Some(&(_, Ok(()))) if flags.dry_run => {
+                info!("DRY-RUN: Version {:?} of {:?} is ready for deploy{}",
+                    context.spec.version, name, target);
+                Outcome::ok("ready")
             }
-            Stage::VerwalterKokkupanek(ref settings) => {
-                match tools::kokkupanek::execute(&context, settings, &vars) {
-                    Ok(()) => {}
-                    Err(e) => {
-                        error!("Version {:?} failed to deploy: {}",
-                            context.spec.version, e);
-                        exit(1);
-                    }
+            Some(&(_, Ok(()))) => {
+                info!("Version {:?} of {:?} is successfully deployed{}",
+                    context.spec.version, name, target);
+                Outcome::ok("deployed")
+            }
+            Some(&(_, Err(ref failure))) => {
+                error!("Version {:?} of {:?} failed to deploy: {}",
+                    context.spec.version, name, failure.error);
+                if !flags.dry_run {
+                    info!("Fix the issue and run with --resume to continue \
+                        from the failed stage");
+                }
+                if started {
+                    code.report_partial();
+                } else {
+                    code.report(failure.kind);
                 }
+                Outcome::failed(failure)
+            }
+            None => {
+                warn!("Deployment {:?} is skipped because of previous failure",
+                    name);
+                Outcome::skipped()
             }
+        };
+        outcomes.insert(&name[..], outcome);
+    }
+    let run = Run::new(Some(&spec.version), &context.target, &flags, &timings);
+    finish(run, outcomes, None, locks, code);
+}
+
+impl<'a> Run<'a> {
+    fn new(version: Option<&'a str>, target: &'a Option<String>,
+        flags: &'a Flags, timings: &'a Timings)
+        -> Run<'a>
+    {
+        Run { version, target, flags, timings }
+    }
+}
+
+impl Outcome {
+    fn ok(status: &'static str) -> Outcome {
+        Outcome { status, failed_stage: None, error: None, error_kind: None }
+    }
+    fn skipped() -> Outcome {
+        Outcome::ok("skipped")
+    }
+    fn failed(failure: &Failure) -> Outcome {
+        Outcome {
+            status: "failed",
+            failed_stage: Some(failure.stage.clone()),
+            error: Some(failure.error.clone()),
+            error_kind: Some(failure.kind),
         }
     }
+}
 
-    if dry_run {
-        info!("DRY-RUN: Version {:?} is ready for deploy",
-            context.spec.version);
-    } else {
-        info!("Version {:?} is successfully deployed", context.spec.version);
+/// Exits because of an error not related to a single deployment
+///
+/// Deployments in `names` are reported as skipped.
+fn abort<D: fmt::Display>(run: Run, kind: ErrorKind, error: D,
+    names: &[String], locks: Vec<lock::DeployLock>, mut code: ExitCode)
+    -> !
+{
+    let error = redact(&error.to_string());
+    error!("{}", error);
+    code.report(kind);
+    let outcomes = names.iter()
+        .map(|name| (&name[..], Outcome::skipped())).collect();
+    finish(run, outcomes, Some(error), locks, code);
+}
+
+/// Releases locks, prints timings and the JSON report if requested, exits
+///
+/// Every exit of the deploy after the config is loaded goes through here.
+fn finish(run: Run, outcomes: BTreeMap<&str, Outcome>,
+    error: Option<String>, locks: Vec<lock::DeployLock>, code: ExitCode)
+    -> !
+{
+    release(locks);
+    run.timings.print();
+    if run.flags.json {
+        let report = Report {
+            version: run.version,
+            target: run.target.as_ref().map(|x| &x[..]),
+            dry_run: run.flags.dry_run,
+            error,
+            deployments: outcomes,
+            timings: run.timings,
+        };
+        println!("{}", redact(&to_string_pretty(&report)
+            .expect("can serialize report")));
     }
-    exit(0);
+    code.exit();
 }
 
+fn release(locks: Vec<lock::DeployLock>) {
+    for lock in locks {
+        lock.release();
+    }
+}
+
+
+/// Checks everything that can be checked without building containers
+///
+/// Validates variables and renders all templates of every stage, for each
+/// deployment and each target (or only the specified one), without running
+/// anything. Container versions are replaced by the project version.
+/// Secrets and missing required variables are replaced by placeholders,
+/// so it can run in CI where they aren't available.
+pub fn preflight(spec: &Spec, vars: &HashMap<String, String>,
+    target: &Option<String>, exit: &mut ExitCode)
+{
+    let targets = match *target {
+        Some(_) => vec![target.clone()],
+        None => {
+            let mut list = Vec::new();
+            if !spec.config.script.is_empty() || spec.config.targets.is_empty()
+            {
+                list.push(None);
+            }
+            list.extend(spec.config.targets.keys().map(|x| Some(x.clone())));
+            list
+        }
+    };
+    for target in &targets {
+        let prefix = match *target {
+            Some(ref name) => format!("target {:?}: ", name),
+            None => String::new(),
+        };
+        let mut tvars = vars.clone();
+        if let Err(e) = spec.config.target_vars(target, &mut tvars) {
+            exit.error(format_args!("{}{}", prefix, e));
+            continue;
+        }
+        let tvars = match vars::validate_offline(&spec.config.variables,
+                                                 tvars)
+        {
+            Ok(vars) => vars,
+            Err(errors) => {
+                for e in errors {
+                    exit.error(format_args!("{}{}", prefix, e));
+                }
+                continue;
+            }
+        };
+        for (name, deployment) in &spec.deployments {
+            if let Err(e) = lock::url(spec, name, target, &tvars) {
+                exit.error(format_args!("{}deployment {:?}: {}",
+                    prefix, name, e));
+            }
+            let mut context = Context {
+                spec,
+                dry_run: true,
+                deployment: name.clone(),
+                target: target.clone(),
+                partial: false,
+                containers: deployment.containers().into_iter()
+                    .map(|c| {
+                        (format!("{}{}", c, spec.config.container_suffix),
+                         Container { version: spec.version.clone() })
+                    })
+                    .collect(),
+                failure: None,
+                previous: None,
+            };
+            for stage in spec.config.target_script(target) {
+                let res = stage_enabled(&context, stage, &tvars)
+                    .and_then(|enabled| if enabled {
+                        check(&context, stage, &tvars)
+                    } else {
+                        Ok(())
+                    });
+                if let Err(e) = res {
+                    exit.error(format_args!("{}deployment {:?}: {}",
+                        prefix, name, e));
+                }
+            }
+            context.failure = Some(Failure {
+                stage: String::from("example"),
+                error: String::from("example error"),
+                kind: ErrorKind::Command,
+            });
+            context.previous = Some(history::Entry::new(&context, true, None));
+            for stage in spec.config.target_on_failure(target) {
+                let res = stage_enabled(&context, stage, &tvars)
+                    .and_then(|enabled| if enabled {
+                        check(&context, stage, &tvars)
+                    } else {
+                        Ok(())
+                    });
+                if let Err(e) = res {
+                    exit.error(format_args!("{}deployment {:?}: \
+                        on_failure: {}", prefix, name, e));
+                }
+            }
+        }
+    }
+}
@@ -5,11 +5,12 @@ use lithos_shim::{ContainerConfig, ContainerKind};
 use quire::{parse_config, Options as Quire};
 use capturing_glob::{glob_with, MatchOptions};
 use serde_json::Value as Json;
+use failure::Error;
 use trimmer::Context;
 
-use exit::ExitCode;
+use exit::{ExitCode, ErrorKind};
 use deploy::config::Config;
-use templates::{GlobVar};
+use templates::{GlobVar, Value};
 use version;
 
 
@@ -43,6 +44,57 @@ pub struct Spec {
     pub deployments: BTreeMap<String, Deployment>,
 }
 
+impl Deployment {
+    /// Names of the containers used by commands and daemons
+    pub fn containers(&self) -> BTreeSet<String> {
+        self.commands.values().map(|x| x.container.clone())
+            .chain(self.daemons.values().map(|x| x.container.clone()))
+            .collect()
+    }
+    /// Names of all the daemons and commands
+    pub fn processes(&self) -> BTreeSet<String> {
+        self.daemons.keys().chain(self.commands.keys()).cloned().collect()
+    }
+    /// Leaves only selected daemons and commands in the deployment
+    ///
+    /// Returns `true` if some processes were filtered out (i.e. this is a
+    /// partial deployment).
+    pub fn filter(&mut self, only: &[String], exclude: &[String]) -> bool {
+        let before = self.processes().len();
+        let keep = |name: &String| {
+            (only.is_empty() || only.contains(name)) && !exclude.contains(name)
+        };
+        self.daemons.retain(|name, _| keep(name));
+        self.commands.retain(|name, _| keep(name));
+        self.processes().len() < before
+    }
+}
+
+impl Spec {
+    /// Path of the vagga config which contains containers of the deployment
+    pub fn vagga_config(&self, deployment: &str) -> Result<String, Error> {
+        let deployment = Value(deployment.to_string());
+        let mut context = Context::new();
+        context.set("deployment", &deployment);
+        self.config.vagga_config.render_key("vagga_config", &context)
+    }
+    /// Returns all vagga configs with containers each one should contain
+    ///
+    /// Unless `vagga-config` refers to `deployment`, it's a single file
+    /// containing containers of all deployments.
+    pub fn vagga_configs(&self)
+        -> Result<BTreeMap<String, BTreeSet<String>>, Error>
+    {
+        let mut result = BTreeMap::new();
+        for (name, dep) in &self.deployments {
+            result.entry(self.vagga_config(name)?)
+                .or_insert_with(BTreeSet::new)
+                .extend(dep.containers());
+        }
+        Ok(result)
+    }
+}
+
 fn str_kind(kind: ContainerKind) -> &'static str {
     match kind {
         ContainerKind::Command => "command",
@@ -54,7 +106,17 @@ fn str_kind(kind: ContainerKind) -> &'static str {
 
 pub fn parse_spec_or_exit(config: Config) -> Spec {
     let mut exit = ExitCode::new();
-    let version = version::get(&config, &mut exit);
+    let spec = parse_spec(config, &mut exit);
+    if !exit.is_ok() {
+        exit.report(ErrorKind::Config);
+    }
+    exit.exit_if_failed();
+    spec
+}
+
+/// Parses spec reporting all errors found, but doesn't exit on them
+pub fn parse_spec(config: Config, exit: &mut ExitCode) -> Spec {
+    let version = version::get(&config, exit);
     debug!("Version {:?}", version);
 
     let mut spec = Spec {
@@ -70,7 +132,7 @@ pub fn parse_spec_or_exit(config: Config) -> Spec {
         require_literal_leading_dot: true,
     };
 
-    let dir_iter = glob_with(&spec.config.deployment_dirs, &gopts)
+    let dir_iter = glob_with(spec.config.deployment_dirs.glob(), &gopts)
         .unwrap_or_else(|e| exit.fatal_error(e));
 
     for dir_entry in dir_iter {
@@ -83,8 +145,9 @@ pub fn parse_spec_or_exit(config: Config) -> Spec {
             }
         };
         let full_pattern = dir_entry.path()
-            .join(&spec.config.lithos_configs);
-        let dir_pattern: GlobVar = (&dir_entry).into();
+            .join(spec.config.lithos_configs.glob());
+        let dir_pattern = GlobVar::new(&dir_entry,
+                                       &spec.config.deployment_dirs);
         let file_iter = glob_with(
             &full_pattern.to_str().expect("path is utf-8"),
                 &gopts)
@@ -100,7 +163,8 @@ pub fn parse_spec_or_exit(config: Config) -> Spec {
                 }
             };
             debug!("Matched {:?}", entry.path());
-            let file_pattern: GlobVar = (&entry).into();
+            let file_pattern = GlobVar::new(&entry,
+                                            &spec.config.lithos_configs);
             let patterns = vec![
                 ("deployment_dirs", &dir_pattern),
                 ("lithos_configs", &file_pattern),
@@ -108,7 +172,8 @@ pub fn parse_spec_or_exit(config: Config) -> Spec {
 
             let mut context = Context::new();
             context.set("patterns", &patterns);
-            let deployment = match spec.config.deployment_name.render(&context)
+            let deployment = match spec.config.deployment_name
+                .render_key("deployment_name", &context)
             {
                 Ok(v) => v,
                 Err(e) => {
@@ -117,7 +182,9 @@ pub fn parse_spec_or_exit(config: Config) -> Spec {
                 }
             };
 
-            let process = match spec.config.process_name.render(&context) {
+            let process = match spec.config.process_name
+                .render_key("process_name", &context)
+            {
                 Ok(v) => v,
                 Err(e) => {
                     exit.error(e);
@@ -194,6 +261,5 @@ pub fn parse_spec_or_exit(config: Config) -> Spec {
         }
     }
 
-    exit.exit_if_failed();
     spec
 }
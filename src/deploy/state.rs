@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, read_dir, remove_file};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use failure::Error;
+use serde_json::from_reader;
+
+use deploy::Context;
+use files::write_json;
+
+
+static STATE_DIR: &str = ".vagga/.wark/deploy-state";
+
+
+/// Progress of the deploy run, so failed deploy can be resumed
+///
+/// Covers all the deployments selected for the run and is keyed by target
+/// and version, so deploys of different versions don't overwrite each
+/// other's state. Saved after each completed stage and cleared only when
+/// the whole run succeeds, states of other versions to the same target are
+/// removed then, as well as on a fresh (not resumed) deploy. Stages are
+/// identified by their index in the `script`, so the list of stage names is
+/// recorded too, to detect changes of the script.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployState {
+    version: String,
+    script: Vec<String>,
+    deployments: BTreeMap<String, Progress>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Progress {
+    containers: BTreeMap<String, String>,
+    completed: BTreeSet<usize>,
+}
+
+
+impl DeployState {
+    /// State of the run deploying `names` with no stages completed
+    pub fn new(ctx: &Context, names: &[String]) -> DeployState {
+        let spec = ctx.spec;
+        let suffix = &spec.config.container_suffix;
+        let version = spec.version.replace('/', "_");
+        let dir = match ctx.target {
+            Some(ref target) => Path::new(STATE_DIR).join(target),
+            None => PathBuf::from(STATE_DIR),
+        };
+        DeployState {
+            version: spec.version.clone(),
+            script: spec.config.script.iter()
+                .map(|s| s.name().to_string())
+                .collect(),
+            deployments: names.iter().map(|dep| {
+                let containers = spec.deployments[dep].containers()
+                    .into_iter()
+                    .filter_map(|c| {
+                        let name = format!("{}{}", c, suffix);
+                        let version = ctx.containers.get(&name)
+                            .map(|x| x.version.clone());
+                        version.map(|v| (name, v))
+                    })
+                    .collect();
+                (dep.clone(), Progress {
+                    containers,
+                    completed: BTreeSet::new(),
+                })
+            }).collect(),
+            path: dir.join(format!("{}.json", version)),
+            dry_run: ctx.dry_run,
+        }
+    }
+    /// Continues previous deploy if there is one
+    ///
+    /// Fails if containers or script are changed since then. Progress of
+    /// deployments not selected for this run is kept. Returns `false` if
+    /// there is nothing to resume.
+    pub fn resume(&mut self) -> Result<bool, Error> {
+        let old: DeployState = match File::open(&self.path) {
+            Ok(f) => {
+                from_reader(BufReader::new(f))
+                .map_err(|e| format_err!("can't read {:?}: {}",
+                                         self.path, e))?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(false);
+            }
+            Err(e) => bail!("can't read {:?}: {}", self.path, e),
+        };
+        if old.version != self.version {
+            bail!("version changed since previous deploy: {:?} -> {:?}",
+                old.version, self.version);
+        }
+        if old.script != self.script {
+            bail!("script changed since previous deploy: [{}] -> [{}]",
+                old.script.join(", "), self.script.join(", "));
+        }
+        let mut resumed = false;
+        for (dep, old) in old.deployments {
+            let progress = match self.deployments.get_mut(&dep) {
+                Some(progress) => progress,
+                None => {
+                    self.deployments.insert(dep, old);
+                    continue;
+                }
+            };
+            for (name, version) in &progress.containers {
+                match old.containers.get(name) {
+                    Some(old) if old == version => {}
+                    Some(old) => {
+                        bail!("container {:?} of {:?} changed since previous \
+                            deploy: {:?} -> {:?}", name, dep, old, version);
+                    }
+                    None => {
+                        bail!("container {:?} of {:?} wasn't in previous \
+                            deploy", name, dep);
+                    }
+                }
+            }
+            resumed = resumed || !old.completed.is_empty();
+            progress.completed = old.completed;
+        }
+        Ok(resumed)
+    }
+    /// Removes state of previous deploys to this target, if any
+    pub fn reset(&self) -> Result<(), Error> {
+        if self.dry_run {
+            return Ok(());
+        }
+        remove_state(&self.path)?;
+        self.remove_stale()
+    }
+    pub fn is_completed(&self, deployment: &str, stage: usize) -> bool {
+        self.deployments.get(deployment)
+            .map(|p| p.completed.contains(&stage))
+            .unwrap_or(false)
+    }
+    /// Returns indexes of stages completed for any of the deployments
+    pub fn completed_stages(&self) -> BTreeSet<usize> {
+        self.deployments.values()
+            .flat_map(|p| p.completed.iter().cloned())
+            .collect()
+    }
+    /// Marks stage of the deployment as completed and saves the state
+    pub fn complete(&mut self, deployment: &str, stage: usize)
+        -> Result<(), Error>
+    {
+        self.deployments.get_mut(deployment)
+            .expect("deployment is in state")
+            .completed.insert(stage);
+        self.save()
+    }
+    /// Clears deployments having all stages completed, removes the state
+    /// if nothing is left, must be called when the whole run succeeds
+    pub fn finish(&mut self) -> Result<(), Error> {
+        let stages = self.script.len();
+        self.deployments.retain(|_, p| p.completed.len() < stages);
+        if self.deployments.is_empty() {
+            self.reset()
+        } else {
+            self.save()?;
+            self.remove_stale()
+        }
+    }
+    fn save(&self) -> Result<(), Error> {
+        if self.dry_run {
+            return Ok(());
+        }
+        write_json(&self.path, self)
+    }
+    /// Removes states of other versions deployed to the same target
+    fn remove_stale(&self) -> Result<(), Error> {
+        let dir = self.path.parent().expect("state has a parent dir");
+        let entries = match read_dir(dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(());
+            }
+            Err(e) => bail!("can't read {:?}: {}", dir, e),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path != self.path &&
+                path.extension().map(|x| x == "json").unwrap_or(false)
+            {
+                debug!("Removing stale deploy state {:?}", path);
+                remove_state(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn remove_state(path: &Path) -> Result<(), Error> {
+    match remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => bail!("can't remove {:?}: {}", path, e),
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::ser::{Serialize, Serializer, SerializeStruct};
+
+
+/// Durations of the deploy phases, shown as a summary at the end of deploy
+///
+/// Total time is counted from creation of the structure, so it should be
+/// created as early as possible.
+#[derive(Debug)]
+pub struct Timings {
+    started: Instant,
+    phases: Vec<(String, Duration)>,
+}
+
+/// Formats duration as seconds with a tenth of second precision
+pub struct Seconds(pub Duration);
+
+#[derive(Serialize)]
+struct Phase<'a> {
+    name: &'a str,
+    seconds: f64,
+}
+
+
+fn seconds(dur: Duration) -> f64 {
+    dur.as_secs() as f64 + dur.subsec_nanos() as f64 / 1e9
+}
+
+impl Timings {
+    pub fn new() -> Timings {
+        Timings {
+            started: Instant::now(),
+            phases: Vec::new(),
+        }
+    }
+    /// Records phase that started at `start` and finished now
+    pub fn add<S: Into<String>>(&mut self, name: S, start: Instant)
+        -> Duration
+    {
+        let dur = start.elapsed();
+        self.phases.push((name.into(), dur));
+        dur
+    }
+    pub fn total(&self) -> Duration {
+        self.started.elapsed()
+    }
+    /// Prints summary table to stderr
+    pub fn print(&self) {
+        let total = String::from("total");
+        let width = self.phases.iter().map(|&(ref name, _)| name.len())
+            .chain(Some(total.len()))
+            .max().unwrap_or(0);
+        eprintln!("Timings:");
+        for &(ref name, dur) in &self.phases {
+            eprintln!("    {:<width$}  {:>8}", name, Seconds(dur).to_string(),
+                width=width);
+        }
+        eprintln!("    {:<width$}  {:>8}", total,
+            Seconds(self.total()).to_string(), width=width);
+    }
+}
+
+impl fmt::Display for Seconds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tenths = (self.0.subsec_nanos() + 50_000_000) / 100_000_000;
+        write!(f, "{}.{}s",
+            self.0.as_secs() + (tenths / 10) as u64, tenths % 10)
+    }
+}
+
+impl Serialize for Timings {
+    fn serialize<S: Serializer>(&self, serializer: S)
+        -> Result<S::Ok, S::Error>
+    {
+        let phases = self.phases.iter()
+            .map(|&(ref name, dur)| Phase { name, seconds: seconds(dur) })
+            .collect::<Vec<_>>();
+        let mut s = serializer.serialize_struct("Timings", 2)?;
+        s.serialize_field("phases", &phases)?;
+        s.serialize_field("total", &seconds(self.total()))?;
+        s.end()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Seconds;
+
+    fn fmt(millis: u64) -> String {
+        Seconds(Duration::from_millis(millis)).to_string()
+    }
+
+    #[test]
+    fn seconds() {
+        assert_eq!(fmt(0), "0.0s");
+        assert_eq!(fmt(49), "0.0s");
+        assert_eq!(fmt(50), "0.1s");
+        assert_eq!(fmt(1234), "1.2s");
+        assert_eq!(fmt(61_000), "61.0s");
+    }
+
+    #[test]
+    fn round_up_to_next_second() {
+        assert_eq!(fmt(950), "1.0s");
+        assert_eq!(fmt(1999), "2.0s");
+    }
+}
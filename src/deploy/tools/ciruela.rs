@@ -1,32 +1,51 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{File, set_permissions};
 use std::io::{self, BufReader};
 use std::os::unix::fs::PermissionsExt;
 use std::process::Command;
-use std::time::Instant;
 use std::path::Path;
 
-use failure::{Error, err_msg, Context as Fail, ResultExt};
+use failure::{Error, Context as Fail, ResultExt};
 use libflate::gzip::Decoder;
+use serde_json::from_reader;
 use tar::Archive;
 use trimmer::{Context as Vars};
 
 use deploy::Context;
 use download::download;
-use templates::{Pattern};
+use exit::ErrorKind;
+use files::write_json;
+use templates::{Condition, Pattern, Value, values};
 
 
 static DEFAULT_CIRUELA: &str = "0.5.11";
+static CIRUELA: &str = "/bin/ciruela";
 
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
+    pub(in deploy) name: Option<String>,
+    pub(in deploy) when: Option<Condition>,
     clusters: Vec<Pattern>,
     dir: Pattern,
     #[serde(default="default_ciruela")]
     ciruela_version: String,
+    /// File where uploaded directories are recorded, so they are not
+    /// uploaded again on next deploy (disabled by default)
+    upload_cache: Option<String>,
 }
 
+/// Image directory to upload
+#[derive(Debug)]
+struct Upload {
+    container: String,
+    version: String,
+    dir: String,
+}
+
+/// Uploaded image version by cluster and directory
+type Uploaded = BTreeMap<String, BTreeMap<String, String>>;
+
 fn default_ciruela() -> String {
     DEFAULT_CIRUELA.to_string()
 }
@@ -41,63 +60,131 @@ fn unpack_ciruela(tar: &Path) -> Result<(), Error> {
             trace!("Skipping {:?}", file.header().path());
             continue;
         }
-        io::copy(&mut file, &mut File::create("/bin/ciruela")?)?;
-        set_permissions("/bin/ciruela", PermissionsExt::from_mode(0o777))?;
+        io::copy(&mut file, &mut File::create(CIRUELA)?)?;
+        set_permissions(CIRUELA, PermissionsExt::from_mode(0o777))?;
         return Ok(());
     }
-    return Err(err_msg("ciruela binary not found in archive"));
+    return Err(ErrorKind::Upload.error("ciruela binary not found in archive"));
+}
+
+/// Renders clusters and directories to upload, doesn't run anything
+fn render(ctx: &Context, set: &Settings, vars: &HashMap<String, String>)
+    -> Result<(Vec<String>, Vec<Upload>), Error>
+{
+    let target = ctx.target.as_ref().map(|x| &x[..]);
+    let vars = values(vars);
+    let containers = ctx.containers.iter()
+        .map(|(name, c)| (Value(name.clone()), Value(c.version.clone())))
+        .collect::<Vec<_>>();
+    let mut context = Vars::new();
+    context.set("vars", &vars);
+    context.set("target", &target);
+    let clusters = set.clusters.iter().map(|h| {
+        h.render_key("ciruela.clusters", &context)
+    }).collect::<Result<Vec<String>, _>>()?;
+
+    let mut uploads = Vec::new();
+    for &(ref name, ref version) in &containers {
+        context.set("container_name", name);
+        context.set("container_version", version);
+        uploads.push(Upload {
+            container: name.0.clone(),
+            version: version.0.clone(),
+            dir: set.dir.render_key("ciruela.dir", &context)?,
+        });
+    }
+    Ok((clusters, uploads))
+}
+
+fn read_uploaded(path: &str) -> Result<Uploaded, Error> {
+    match File::open(path) {
+        Ok(f) => {
+            from_reader(BufReader::new(f))
+            .map_err(|e| format_err!("can't read {:?}: {}", path, e))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            Ok(Uploaded::new())
+        }
+        Err(e) => Err(format_err!("can't read {:?}: {}", path, e)),
+    }
+}
+
+fn write_uploaded(path: &str, clusters: &[String], uploads: &[Upload])
+    -> Result<(), Error>
+{
+    let mut data = read_uploaded(path)?;
+    for cluster in clusters {
+        let dirs = data.entry(cluster.clone()).or_insert_with(BTreeMap::new);
+        for up in uploads {
+            dirs.insert(up.dir.clone(), up.version.clone());
+        }
+    }
+    write_json(path, &data)
+}
+
+pub(in deploy) fn check(ctx: &Context,
+    set: &Settings, vars: &HashMap<String, String>)
+    -> Result<(), Error>
+{
+    render(ctx, set, vars).map(|_| ())
 }
 
 pub(in deploy) fn execute(ctx: &Context,
     set: &Settings, vars: &HashMap<String, String>)
     -> Result<(), Error>
 {
-    let ciruela = Path::new("/bin/ciruela");
-    if !ciruela.exists() && !ctx.dry_run {
+    let (clusters, mut uploads) = render(ctx, set, vars)?;
+    if let Some(ref path) = set.upload_cache {
+        let uploaded = read_uploaded(path)?;
+        let (skip, upload) = uploads.into_iter().partition::<Vec<_>, _>(|up| {
+            clusters.iter().all(|c| {
+                uploaded.get(c).and_then(|dirs| dirs.get(&up.dir))
+                    == Some(&up.version)
+            })
+        });
+        uploads = upload;
+        if skip.len() > 0 {
+            info!("Already uploaded, skipping: {}", skip.iter()
+                .map(|up| &up.container[..])
+                .collect::<Vec<_>>().join(", "));
+        }
+        if uploads.is_empty() {
+            info!("All containers are already uploaded");
+            return Ok(());
+        }
+    }
+    if !Path::new(CIRUELA).exists() && !ctx.dry_run {
         let tar = download(&format!("https://github.com/tailhook/ciruela/\
             releases/download/v{0}/ciruela-static-v{0}.tar.gz",
             set.ciruela_version), false)?;
         unpack_ciruela(&tar).context("can't unpack ciruela")?;
     }
-
-
-    let mut context = Vars::new();
-    context.set("vars", vars);
-    let clusters = set.clusters.iter().map(|h| {
-        h.render(&context)
-    }).collect::<Result<Vec<String>, _>>()
-        .map_err(|e| err_msg(format!("Can't render host pattern: {}", e)))?;
-
-    let mut cmd = Command::new(ciruela);
+    let mut cmd = Command::new(CIRUELA);
     cmd.arg("sync");
-    for (name, container) in &ctx.containers {
-        context.set("container_name", name);
-        context.set("container_version", &container.version);
-        let dir = set.dir.render(&context)
-            .map_err(|e| err_msg(format!("Can't render dir pattern: {}", e)))?;
+    for up in &uploads {
         cmd.arg("--append-weak");
         cmd.arg(format!("/vagga/base/.roots/{}/root:{}",
-                        container.version, dir));
+                        up.version, up.dir));
     }
     cmd.args(&clusters);
     if ctx.dry_run {
         info!("Would run: {:?}", cmd);
     } else {
         info!("Running: {:?}", cmd);
-        let start = Instant::now();
         let status = cmd.status()
             .with_context(|e| {
                 error!("Ciruela error: {}", e);
                 Fail::new("failed to run ciruela")
             })?;
-        if status.success() {
-            let dur = start.elapsed();
-            if dur.as_secs() > 2 {
-                info!("Upload done in {}s", dur.as_secs());
-            }
-        } else {
+        if !status.success() {
             error!("Ciruela {}", status);
-            return Err(err_msg("ciruela failed"));
+            return Err(ErrorKind::Upload.error("ciruela failed"));
+        }
+        info!("Uploaded: {}", uploads.iter()
+            .map(|up| &up.container[..])
+            .collect::<Vec<_>>().join(", "));
+        if let Some(ref path) = set.upload_cache {
+            write_uploaded(path, &clusters, &uploads)?;
         }
     }
     Ok(())
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::process::Command;
+
+use failure::{Error, err_msg};
+use trimmer::{Context as Vars};
+
+use deploy::Context;
+use templates::{Condition, Pattern, Value, values};
+
+
+/// Runs a local command, useful for notifications in `on_failure`
+///
+/// Arguments are patterns which may refer to `vars`, `target`,
+/// `deployment`, `version`, `previous_version` (last version completely
+/// deployed from this machine, empty if unknown) and `failure` (with
+/// `stage`, `error` and `kind` attributes, only in `on_failure` stages).
+/// The same values are passed in `WARK_*` environment variables.
+#[derive(Debug, Deserialize)]
+pub struct Settings {
+    pub(in deploy) name: Option<String>,
+    pub(in deploy) when: Option<Condition>,
+    run: Vec<Pattern>,
+}
+
+/// Builds command, doesn't run anything
+fn command(ctx: &Context, set: &Settings, vars: &HashMap<String, String>)
+    -> Result<Command, Error>
+{
+    let target = ctx.target.as_ref().map(|x| &x[..]);
+    let vars = values(vars);
+    let deployment = Value(ctx.deployment.clone());
+    let version = Value(ctx.spec.version.clone());
+    let previous = Value(ctx.previous.as_ref()
+        .map(|x| x.version.clone()).unwrap_or_default());
+    let mut context = Vars::new();
+    context.set("vars", &vars);
+    context.set("target", &target);
+    context.set("deployment", &deployment);
+    context.set("version", &version);
+    context.set("previous_version", &previous);
+    if let Some(ref failure) = ctx.failure {
+        context.set("failure", failure);
+    }
+    let args = set.run.iter().map(|arg| {
+        arg.render_key("command.run", &context)
+    }).collect::<Result<Vec<String>, _>>()?;
+    if args.is_empty() {
+        return Err(err_msg("run must not be empty"));
+    }
+
+    let mut cmd = Command::new(&args[0]);
+    cmd.args(&args[1..]);
+    cmd.env("WARK_DEPLOYMENT", &ctx.deployment);
+    cmd.env("WARK_VERSION", &ctx.spec.version);
+    if let Some(ref target) = ctx.target {
+        cmd.env("WARK_TARGET", target);
+    }
+    if let Some(ref previous) = ctx.previous {
+        cmd.env("WARK_PREVIOUS_VERSION", &previous.version);
+    }
+    if let Some(ref failure) = ctx.failure {
+        cmd.env("WARK_FAILED_STAGE", &failure.stage);
+        cmd.env("WARK_ERROR", &failure.error);
+        cmd.env("WARK_ERROR_KIND", failure.kind.name());
+    }
+    Ok(cmd)
+}
+
+pub(in deploy) fn check(ctx: &Context,
+    set: &Settings, vars: &HashMap<String, String>)
+    -> Result<(), Error>
+{
+    command(ctx, set, vars).map(|_| ())
+}
+
+pub(in deploy) fn execute(ctx: &Context,
+    set: &Settings, vars: &HashMap<String, String>)
+    -> Result<(), Error>
+{
+    let mut cmd = command(ctx, set, vars)?;
+    if ctx.dry_run {
+        info!("Would run: {:?}", cmd);
+        return Ok(());
+    }
+    info!("Running: {:?}", cmd);
+    let status = cmd.status()
+        .map_err(|e| format_err!("can't run {:?}: {}", cmd, e))?;
+    if !status.success() {
+        bail!("command {:?} failed: {}", cmd, status);
+    }
+    Ok(())
+}
@@ -1,33 +1,40 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::time::Duration;
 use std::sync::Arc;
 
-use failure::{Error, err_msg, Fail};
-use futures::{Future, Stream, Async, Sink};
-use futures::future::{loop_fn, Loop, Either, ok, err, FutureResult, empty};
+use failure::{Error, Fail};
+use futures::{Future, Async};
+use futures::future::{loop_fn, Loop, Either, ok, err, FutureResult};
 use futures::sync::oneshot;
-use futures::stream::{once};
 use ns_env_config;
 use rand::{thread_rng, Rng};
 use tk_easyloop::{self, handle, timeout};
 use trimmer::{Context as Vars};
-use tokio_core::net::TcpStream;
 use serde_json::{to_vec, to_value, from_slice, Value as Json};
 use serde_json::{to_string_pretty};
 use tk_http::{Version, Status};
 use tk_http::client::{RecvMode, Head, Error as HError, Encoder, EncoderDone};
-use tk_http::client::{Codec, Config, Proto};
+use tk_http::client::Codec;
 
 use deploy::Context;
-use templates::{Pattern};
+use exit::ErrorKind;
+use http;
+use redact::redact;
+use templates::{Condition, Pattern, values};
 
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all="kebab-case")]
 pub struct Settings {
+    pub(in deploy) name: Option<String>,
+    pub(in deploy) when: Option<Condition>,
     hosts: Vec<Pattern>,
     slug: Pattern,
     deployment_graphql: Pattern,
+    /// Submit last version completely deployed from this machine instead
+    /// of the current one, for rolling back in `on_failure`
+    #[serde(default)]
+    previous: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -50,6 +57,9 @@ struct PostNewDeployment(Option<oneshot::Sender<Json>>, Arc<Vec<u8>>);
 #[derive(Debug, Serialize)]
 pub struct NewDeployment<'a> {
     version: &'a str,
+    /// Processes which are not listed must be kept intact
+    #[serde(skip_serializing_if="is_false")]
+    partial: bool,
     daemons: Vec<NewDaemon<'a>>,
     commands: Vec<NewCommand<'a>>,
 }
@@ -71,71 +81,108 @@ pub struct NewCommand<'a> {
     memory_limit: f64,
 }
 
-pub(in deploy) fn execute(ctx: &Context,
-    set: &Settings, vars: &HashMap<String, String>)
-    -> Result<(), Error>
+/// Renders hosts and the request, doesn't connect anywhere
+fn request(ctx: &Context, set: &Settings, vars: &HashMap<String, String>)
+    -> Result<(Vec<String>, GraphqlRequest<'static>), Error>
 {
+    let target = ctx.target.as_ref().map(|x| &x[..]);
+    let vars = values(vars);
     let mut context = Vars::new();
-    context.set("vars", vars);
+    context.set("vars", &vars);
+    context.set("target", &target);
 
     let hosts = set.hosts.iter().map(|h| {
-        h.render(&context)
-    }).collect::<Result<Vec<String>, _>>()
-        .map_err(|e| err_msg(format!("Can't render host pattern: {}", e)))?;
-    let slug = set.slug.render(&context)
-        .map_err(|e| err_msg(format!("Can't render slug pattern: {}", e)))?;
-    let deployment_graphql = set.deployment_graphql.render(&context)
-        .map_err(|e| err_msg(
-            format!("Can't render deployemnt-graphql: {}", e)))?;
+        h.render_key("verwalter_kokkupanek.hosts", &context)
+    }).collect::<Result<Vec<String>, _>>()?;
+    let slug = set.slug.render_key("verwalter_kokkupanek.slug", &context)?;
+    let deployment_graphql = set.deployment_graphql
+        .render_key("verwalter_kokkupanek.deployment-graphql", &context)?;
 
     if hosts.is_empty() {
-        return Err(err_msg("hosts must not be empty"));
+        return Err(ErrorKind::Config.error("hosts must not be empty"));
     }
 
     let dep = match ctx.spec.deployments.get(&ctx.deployment) {
         Some(dep) => dep,
         None => {
-            return Err(err_msg(format!("no deployment {:?} found",
-                ctx.deployment)));
+            return Err(ErrorKind::Config.error(format_args!(
+                "no deployment {:?} found", ctx.deployment)));
+        }
+    };
+
+    let current = ctx.containers.iter()
+        .map(|(name, c)| (name.clone(), c.version.clone()))
+        .collect::<BTreeMap<_, _>>();
+    let (version, containers) = if set.previous {
+        match ctx.previous {
+            Some(ref previous) => (&previous.version, &previous.containers),
+            None => {
+                return Err(ErrorKind::Validation.error(format_args!(
+                    "no previous version of {:?} was deployed \
+                    from this machine", ctx.deployment)));
+            }
         }
+    } else {
+        (&ctx.spec.version, &current)
     };
 
     let mut gvars = HashMap::new();
     gvars.insert("slug", Json::String(slug));
     gvars.insert("config", to_value(&NewDeployment {
-        version: &ctx.spec.version,
+        version,
+        partial: ctx.partial,
         daemons: dep.daemons.values().map(|d| Ok(NewDaemon {
-            image: &ctx.containers
+            image: containers
                 .get(&(d.container.clone() + &ctx.spec.config.container_suffix))
                 .ok_or_else(|| {
-                    err_msg(format!("container {:?} not found", d.container))
-                })?.version,
+                    ErrorKind::Config.error(format_args!(
+                        "container {:?} not found", d.container))
+                })?,
             config: &d.config_path,
             cpu_shares: d.config.cpu_shares as i32,
             memory_limit: d.config.memory_limit as f64,
         })).collect::<Result<_, Error>>()?,
         commands: dep.commands.values().map(|c| Ok(NewCommand {
-            image: &ctx.containers
+            image: containers
                 .get(&(c.container.clone() + &ctx.spec.config.container_suffix))
                 .ok_or_else(|| {
-                    err_msg(format!("container {:?} not found", c.container))
-                })?.version,
+                    ErrorKind::Config.error(format_args!(
+                        "container {:?} not found", c.container))
+                })?,
             config: &c.config_path,
             cpu_shares: c.config.cpu_shares as i32,
             memory_limit: c.config.memory_limit as f64,
         })).collect::<Result<_, Error>>()?,
     }).expect("new deployment serializes fine"));
 
-    let req_data = GraphqlRequest {
+    Ok((hosts, GraphqlRequest {
         query: deployment_graphql,
         variables: gvars,
-    };
+    }))
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+pub(in deploy) fn check(ctx: &Context,
+    set: &Settings, vars: &HashMap<String, String>)
+    -> Result<(), Error>
+{
+    request(ctx, set, vars).map(|_| ())
+}
+
+pub(in deploy) fn execute(ctx: &Context,
+    set: &Settings, vars: &HashMap<String, String>)
+    -> Result<(), Error>
+{
+    let (hosts, req_data) = request(ctx, set, vars)?;
     let req = Arc::new(to_vec(&req_data)
         .expect("can serialize graphql request"));
 
     if ctx.dry_run {
-        info!("Would execute graphql: {}", to_string_pretty(&req_data)
-            .expect("can serialize graphql request"));
+        info!("Would execute graphql: {}", redact(&to_string_pretty(&req_data)
+            .expect("can serialize graphql request")));
         return Ok(());
     }
 
@@ -147,34 +194,11 @@ pub(in deploy) fn execute(ctx: &Context,
             let ns = ns.clone();
             let req = req.clone();
             debug!("Connecting to {:?}", host);
-            ns.resolve_auto(host, 8379).map_err(|e| e.into())
-            .and_then(|addr| {
-                addr.pick_one()
-                    .ok_or_else(|| err_msg("could not resolve name"))
-            })
+            http::resolve(&ns, host, 8379)
             .and_then(|addr| {
                 debug!("Connecting to ip {}", addr);
-                TcpStream::connect(&addr, &handle())
-                .map_err(move |e| {
-                    err_msg(format!("error connecting to {}: {}", addr, e))
-                })
-                .and_then(|sock| {
-                    let (tx, rx) = oneshot::channel();
-                    let proto = Proto::new(sock,
-                        &handle(), &Arc::new(Config::new()));
-                    proto.send_all(once::<_, HError>(Ok(
-                        GetLeaderCodec(Some(tx))
-                    )).chain(empty().into_stream()))
-                    .select2(rx)
-                    .then(|res| match res {
-                        Ok(Either::B((val, _))) => Ok(val),
-                        Err(Either::A((e, _))) => Err(e.into()),
-                        Err(Either::B((e, _))) => Err(e.into()),
-                        _ => {
-                            Err(err_msg("request error")) // TODO(tailhook)
-                        }
-                    })
-                })
+                let (tx, rx) = oneshot::channel();
+                http::request(addr, GetLeaderCodec(Some(tx)), rx)
                 .and_then(move |leader| {
                     match leader {
                         LeaderResult::SameHost => {
@@ -183,44 +207,15 @@ pub(in deploy) fn execute(ctx: &Context,
                         }
                         LeaderResult::OtherHost(name) => {
                             info!("Leader name {:?}", name);
-                            Either::B(ns.resolve_auto(&name, 8379)
-                                .map_err(move |e| {
-                                    err_msg(format!(
-                                        "Error resolving {:?}: {}", name, e))
-                                })
-                                .and_then(|addr| {
-                                    addr.pick_one().ok_or_else(||
-                                        err_msg("could not resolve \
-                                                 leader name"))
-                                }))
+                            Either::B(http::resolve(&ns, &name, 8379))
                         }
                     }
                 })
             })
-            .and_then(|addr| {
+            .and_then(move |addr| {
                 debug!("Connecting to leader at ip {}", addr);
-                TcpStream::connect(&addr, &handle())
-                .map_err(move |e| {
-                    err_msg(format!("error connecting to leader at {}: {}",
-                        addr, e))
-                })
-            })
-            .and_then(move |sock| {
                 let (tx, rx) = oneshot::channel();
-                let proto = Proto::new(sock,
-                    &handle(), &Arc::new(Config::new()));
-                proto.send_all(once::<_, HError>(Ok(
-                    PostNewDeployment(Some(tx), req.clone())
-                )).chain(empty().into_stream()))
-                .select2(rx)
-                .then(|res| match res {
-                    Ok(Either::B((val, _))) => Ok(val),
-                    Err(Either::A((e, _))) => Err(e.into()),
-                    Err(Either::B((e, _))) => Err(e.into()),
-                    _ => {
-                        Err(err_msg("request error")) // TODO(tailhook)
-                    }
-                })
+                http::request(addr, PostNewDeployment(Some(tx), req), rx)
             })
             .then(move |res| match res {
                 Ok(info) => {
@@ -228,11 +223,10 @@ pub(in deploy) fn execute(ctx: &Context,
                     info!("Response {:#?}", info);
                     Either::A(ok(Loop::Break(())))
                 }
-                Err(ref e) if niter > 20 => {
+                Err(e) => if niter > 20 {
                     error!("Error: {}. Bailing out...", e);
-                    Either::A(err(()))
-                }
-                Err(ref e) => {
+                    Either::A(err(e))
+                } else {
                     error!("Error: {}. Will retry in a second...", e);
                     Either::B(timeout(Duration::new(1, 0))
                         .map(move |()| Loop::Continue(niter+1))
@@ -240,7 +234,12 @@ pub(in deploy) fn execute(ctx: &Context,
                 }
             })
         })
-    }).map_err(|()| err_msg("failed to execute verwalter action"))
+    }).map_err(|e| {
+        // network error if verwalter is unreachable, otherwise it's
+        // verwalter which fails to accept the deployment
+        let message = format!("failed to execute verwalter action: {}", e);
+        ErrorKind::of(&e).unwrap_or(ErrorKind::Verwalter).context(message, e)
+    })
 }
 
 #[derive(Debug, Fail)]
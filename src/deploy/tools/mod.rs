@@ -1,2 +1,3 @@
 pub mod ciruela;
+pub mod command;
 pub mod kokkupanek;
@@ -0,0 +1,439 @@
+use std::env;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+
+use regex::Regex;
+use trimmer::{Variable};
+
+use redact::add_secret;
+
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all="kebab-case")]
+pub enum Type {
+    String,
+    Bool,
+    Integer,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all="kebab-case")]
+pub struct Declaration {
+    pub description: Option<String>,
+    pub default: Option<String>,
+    #[serde(rename="type")]
+    pub kind: Type,
+    #[serde(default)]
+    pub values: Vec<String>,
+    pub regex: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub secret: bool,
+    pub env: Option<String>,
+    pub file: Option<String>,
+}
+
+/// Parses `-D NAME=VALUE` arguments, bare `-D NAME` means `NAME=true`
+pub fn parse_defines(pairs: &[String]) -> HashMap<String, String> {
+    let mut vars = HashMap::new();
+    for pair in pairs {
+        let mut iter = pair.splitn(2, '=');
+        match (iter.next(), iter.next()) {
+            (Some(key), Some(val)) => {
+                vars.insert(key.to_string(), val.to_string());
+            }
+            (Some(key), None) => {
+                vars.insert(key.to_string(), "true".to_string());
+            }
+            _ => unreachable!(),
+        }
+    }
+    return vars;
+}
+
+/// Registers values of secret variables available from environment or
+/// files for redaction, for commands which don't validate variables
+pub fn register_secrets(decl: &BTreeMap<String, Declaration>) {
+    for (name, d) in decl {
+        if d.secret {
+            d.external_value(name).ok();
+        }
+    }
+}
+
+/// Checks variables passed on the command-line against declarations
+///
+/// Values not passed explicitly are taken from the declared environment
+/// variable, then file, then default. Values of secret variables are
+/// registered for redaction in logs.
+///
+/// Returns variables with defaults filled in or a list of all the problems
+/// found. When nothing is declared in the config any variable is accepted,
+/// to keep configs written before `variables:` was introduced working.
+pub fn validate(decl: &BTreeMap<String, Declaration>,
+    vars: HashMap<String, String>)
+    -> Result<HashMap<String, String>, Vec<String>>
+{
+    validate_vars(decl, vars, false)
+}
+
+/// Same as `validate` but doesn't need secrets, for `wark check`
+///
+/// Secret variables not passed explicitly are never read from environment
+/// or files, and missing required variables are not an error. Both get
+/// a placeholder value, which isn't checked against constraints.
+pub fn validate_offline(decl: &BTreeMap<String, Declaration>,
+    vars: HashMap<String, String>)
+    -> Result<HashMap<String, String>, Vec<String>>
+{
+    validate_vars(decl, vars, true)
+}
+
+fn validate_vars(decl: &BTreeMap<String, Declaration>,
+    mut vars: HashMap<String, String>, offline: bool)
+    -> Result<HashMap<String, String>, Vec<String>>
+{
+    if decl.is_empty() {
+        return Ok(vars);
+    }
+    let mut errors = Vec::new();
+    for name in vars.keys() {
+        if !decl.contains_key(name) {
+            errors.push(format!("unknown variable {:?}, declared are: {}",
+                name, decl.keys().map(|x| &x[..])
+                    .collect::<Vec<_>>().join(", ")));
+        }
+    }
+    for (name, d) in decl {
+        let value = match vars.remove(name) {
+            Some(value) => {
+                if d.secret {
+                    add_secret(&value);
+                    warn!("Secret variable {:?} is passed on the command-line, \
+                        consider using {}", name,
+                        d.env.as_ref().map(|x| format!("env {:?}", x))
+                        .or(d.file.as_ref().map(|x| format!("file {:?}", x)))
+                        .unwrap_or_else(|| String::from("env or file")));
+                }
+                value
+            }
+            None if offline && d.secret => {
+                vars.insert(name.clone(), d.placeholder());
+                continue;
+            }
+            None => match d.external_value(name) {
+                Ok(Some(value)) => value,
+                Ok(None) => match d.default {
+                    Some(ref value) => value.clone(),
+                    None if d.required && offline => {
+                        vars.insert(name.clone(), d.placeholder());
+                        continue;
+                    }
+                    None if d.required => {
+                        errors.push(format!("variable {:?} is required",
+                                            name));
+                        continue;
+                    }
+                    None => continue,
+                },
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            },
+        };
+        match d.check(name, value) {
+            Ok(value) => {
+                if d.secret {
+                    add_secret(&value);
+                }
+                vars.insert(name.clone(), value);
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+    if errors.is_empty() {
+        Ok(vars)
+    } else {
+        Err(errors)
+    }
+}
+
+impl Declaration {
+    fn external_value(&self, name: &str) -> Result<Option<String>, String> {
+        if let Some(ref var) = self.env {
+            match env::var(var) {
+                Ok(value) => {
+                    if self.secret {
+                        add_secret(&value);
+                    }
+                    return Ok(Some(value));
+                }
+                Err(env::VarError::NotPresent) => {}
+                Err(e) => {
+                    return Err(format!("variable {:?}: can't read env {:?}: {}",
+                        name, var, e));
+                }
+            }
+        }
+        if let Some(ref path) = self.file {
+            let mut buf = String::with_capacity(100);
+            File::open(path)
+                .and_then(|mut f| f.read_to_string(&mut buf))
+                .map_err(|e| format!("variable {:?}: can't read file {:?}: {}",
+                    name, path, e))?;
+            let value = buf.trim_right_matches(|c| c == '\n' || c == '\r')
+                .to_string();
+            if self.secret {
+                add_secret(&value);
+            }
+            return Ok(Some(value));
+        }
+        Ok(None)
+    }
+    /// Value of the right type used instead of unknown one
+    fn placeholder(&self) -> String {
+        match self.kind {
+            Type::String => self.values.first().cloned()
+                .unwrap_or_else(|| String::from("placeholder")),
+            Type::Bool => String::from("true"),
+            Type::Integer => self.values.first().cloned()
+                .unwrap_or_else(|| String::from("0")),
+        }
+    }
+    fn check(&self, name: &str, value: String) -> Result<String, String> {
+        let value = match self.kind {
+            Type::String => value,
+            Type::Bool => match &value[..] {
+                "true" | "yes" | "1" => "true".to_string(),
+                "false" | "no" | "0" => "false".to_string(),
+                _ => return Err(format!(
+                    "variable {:?} must be a boolean, got {:?}",
+                    name, value)),
+            },
+            Type::Integer => match value.parse::<i64>() {
+                Ok(x) => x.to_string(),
+                Err(_) => return Err(format!(
+                    "variable {:?} must be an integer, got {:?}",
+                    name, value)),
+            },
+        };
+        if !self.values.is_empty() && !self.values.contains(&value) {
+            return Err(format!("variable {:?} must be one of {:?}, got {:?}",
+                name, self.values, value));
+        }
+        if let Some(ref regex) = self.regex {
+            let re = Regex::new(&format!("^(?:{})$", regex))
+                .map_err(|e| format!("variable {:?} has invalid regex: {}",
+                                     name, e))?;
+            if !re.is_match(&value) {
+                return Err(format!("variable {:?} must match {:?}, got {:?}",
+                    name, regex, value));
+            }
+        }
+        Ok(value)
+    }
+    /// Short human-readable summary of constraints, used in listings
+    pub fn summary(&self) -> String {
+        let mut res = match self.kind {
+            Type::String => String::from("string"),
+            Type::Bool => String::from("bool"),
+            Type::Integer => String::from("integer"),
+        };
+        if self.required {
+            res.push_str(", required");
+        }
+        if self.secret {
+            res.push_str(", secret");
+        }
+        if let Some(ref var) = self.env {
+            res.push_str(&format!(", from env: {}", var));
+        }
+        if let Some(ref path) = self.file {
+            res.push_str(&format!(", from file: {:?}", path));
+        }
+        if let Some(ref val) = self.default {
+            if self.secret {
+                res.push_str(", default: ***");
+            } else {
+                res.push_str(&format!(", default: {:?}", val));
+            }
+        }
+        if !self.values.is_empty() {
+            res.push_str(&format!(", one of: {}", self.values.join("|")));
+        }
+        if let Some(ref regex) = self.regex {
+            res.push_str(&format!(", matches: {:?}", regex));
+        }
+        return res;
+    }
+}
+
+impl<'render> Variable<'render> for Declaration {
+    fn typename(&self) -> &'static str {
+        "Declaration"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::collections::{BTreeMap, HashMap};
+    use std::fs::File;
+    use std::io::Write;
+    use std::process;
+
+    use super::{Declaration, Type, validate, parse_defines};
+
+    fn decl(kind: Type) -> Declaration {
+        Declaration {
+            description: None,
+            default: None,
+            kind,
+            values: Vec::new(),
+            regex: None,
+            required: false,
+            secret: false,
+            env: None,
+            file: None,
+        }
+    }
+
+    fn file(name: &str, data: &str) -> String {
+        let path = env::temp_dir()
+            .join(format!("wark-vars-{}-{}", name, process::id()));
+        File::create(&path).unwrap().write_all(data.as_bytes()).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn one(name: &str, d: Declaration) -> BTreeMap<String, Declaration> {
+        let mut decl = BTreeMap::new();
+        decl.insert(name.to_string(), d);
+        decl
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn defines() {
+        assert_eq!(parse_defines(&["a=1=2".into(), "b".into()]),
+                   vars(&[("a", "1=2"), ("b", "true")]));
+    }
+
+    #[test]
+    fn explicit_over_default() {
+        let mut d = decl(Type::String);
+        d.default = Some("default".into());
+        let decl = one("x", d);
+        let res = validate(&decl, vars(&[("x", "cli")])).unwrap();
+        assert_eq!(res["x"], "cli");
+        let res = validate(&decl, vars(&[])).unwrap();
+        assert_eq!(res["x"], "default");
+    }
+
+    #[test]
+    fn explicit_over_env() {
+        env::set_var("WARK_TEST_EXPLICIT", "env");
+        let mut d = decl(Type::String);
+        d.env = Some("WARK_TEST_EXPLICIT".into());
+        d.file = Some(file("explicit", "file\n"));
+        d.default = Some("default".into());
+        let res = validate(&one("x", d), vars(&[("x", "cli")])).unwrap();
+        assert_eq!(res["x"], "cli");
+    }
+
+    #[test]
+    fn env_over_file() {
+        env::set_var("WARK_TEST_ENV", "env");
+        let mut d = decl(Type::String);
+        d.env = Some("WARK_TEST_ENV".into());
+        d.file = Some(file("env", "file\n"));
+        d.default = Some("default".into());
+        let res = validate(&one("x", d), vars(&[])).unwrap();
+        assert_eq!(res["x"], "env");
+    }
+
+    #[test]
+    fn file_over_default() {
+        let mut d = decl(Type::String);
+        d.env = Some("WARK_TEST_UNSET".into());
+        d.file = Some(file("file", "file\r\n"));
+        d.default = Some("default".into());
+        let res = validate(&one("x", d), vars(&[])).unwrap();
+        assert_eq!(res["x"], "file");
+    }
+
+    #[test]
+    fn missing_file() {
+        let mut d = decl(Type::String);
+        d.file = Some("/nonexistent/wark-test".into());
+        d.default = Some("default".into());
+        assert!(validate(&one("x", d), vars(&[])).is_err());
+    }
+
+    #[test]
+    fn default_is_checked() {
+        let mut d = decl(Type::Integer);
+        d.default = Some("007".into());
+        let res = validate(&one("x", d), vars(&[])).unwrap();
+        assert_eq!(res["x"], "7");
+
+        let mut d = decl(Type::String);
+        d.default = Some("c".into());
+        d.values = vec!["a".into(), "b".into()];
+        assert!(validate(&one("x", d), vars(&[])).is_err());
+    }
+
+    #[test]
+    fn types() {
+        let bools = one("x", decl(Type::Bool));
+        assert_eq!(validate(&bools, vars(&[("x", "yes")])).unwrap()["x"],
+                   "true");
+        assert_eq!(validate(&bools, vars(&[("x", "0")])).unwrap()["x"],
+                   "false");
+        assert!(validate(&bools, vars(&[("x", "maybe")])).is_err());
+        let ints = one("x", decl(Type::Integer));
+        assert!(validate(&ints, vars(&[("x", "1.5")])).is_err());
+    }
+
+    #[test]
+    fn regex() {
+        let mut d = decl(Type::String);
+        d.regex = Some("[a-z]+|x[0-9]".into());
+        let decl = one("x", d);
+        assert!(validate(&decl, vars(&[("x", "abc")])).is_ok());
+        assert!(validate(&decl, vars(&[("x", "x1")])).is_ok());
+        // regex is anchored, including every alternative
+        assert!(validate(&decl, vars(&[("x", "abc1")])).is_err());
+        assert!(validate(&decl, vars(&[("x", "1x1")])).is_err());
+    }
+
+    #[test]
+    fn required() {
+        let mut d = decl(Type::String);
+        d.required = true;
+        assert_eq!(validate(&one("x", d), vars(&[])).unwrap_err(),
+                   vec![String::from("variable \"x\" is required")]);
+        let res = validate(&one("x", decl(Type::String)), vars(&[]));
+        assert!(!res.unwrap().contains_key("x"));
+    }
+
+    #[test]
+    fn unknown() {
+        let decl = one("x", decl(Type::Bool));
+        let errors = validate(&decl, vars(&[("x", "yes"), ("y", "1")]))
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("unknown variable \"y\""), "{}", errors[0]);
+    }
+
+    #[test]
+    fn undeclared() {
+        let res = validate(&BTreeMap::new(), vars(&[("y", "1")])).unwrap();
+        assert_eq!(res["y"], "1");
+    }
+}
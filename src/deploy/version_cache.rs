@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+
+use failure::Error;
+use serde_json::from_reader;
+
+use files::write_json;
+
+
+/// Container versions from previous runs, keyed by container name
+///
+/// Version is reused only if version hash of the container (see
+/// `local::vagga::version_hash`) is the same as when the version was
+/// recorded.
+#[derive(Debug)]
+pub struct VersionCache {
+    path: String,
+    entries: BTreeMap<String, Entry>,
+    changed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    hash: String,
+    version: String,
+}
+
+
+impl VersionCache {
+    /// Reads the cache file, `refresh` discards all recorded versions
+    pub fn load(path: &str, refresh: bool) -> Result<VersionCache, Error> {
+        let entries = if refresh {
+            BTreeMap::new()
+        } else {
+            match File::open(path) {
+                Ok(f) => {
+                    from_reader(BufReader::new(f))
+                    .map_err(|e| format_err!("can't read {:?}: {}", path, e))?
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    BTreeMap::new()
+                }
+                Err(e) => bail!("can't read {:?}: {}", path, e),
+            }
+        };
+        Ok(VersionCache {
+            path: path.to_string(),
+            entries,
+            changed: refresh,
+        })
+    }
+    pub fn get(&self, container: &str, hash: &str) -> Option<&str> {
+        match self.entries.get(container) {
+            Some(e) if e.hash == hash => Some(&e.version),
+            _ => None,
+        }
+    }
+    pub fn insert(&mut self, container: &str, hash: String,
+        version: String)
+    {
+        self.entries.insert(container.to_string(),
+            Entry { hash, version });
+        self.changed = true;
+    }
+    /// Writes the cache file if anything changed
+    pub fn save(&self) -> Result<(), Error> {
+        if !self.changed {
+            return Ok(());
+        }
+        write_json(&self.path, &self.entries)
+    }
+}
@@ -0,0 +1,107 @@
+use std::env;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use failure::Error;
+use quire::{parse_config, Options};
+use quire::validate::{Structure, Scalar, Mapping};
+
+use exit::ErrorKind;
+
+
+static LOCAL_NAMES: &[&str] = &[".wark.yaml", "wark.yaml"];
+
+
+#[derive(Debug, Deserialize)]
+struct UserConfig {
+    destinations: BTreeMap<String, String>,
+}
+
+impl UserConfig {
+    fn validator<'x>() -> Structure<'x> {
+        Structure::new()
+        .member("destinations", Mapping::new(Scalar::new(), Scalar::new()))
+    }
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME") {
+        return Some(Path::new(&dir).join("wark/config.yaml"));
+    }
+    env::var_os("HOME").map(|home| {
+        Path::new(&home).join(".config/wark/config.yaml")
+    })
+}
+
+fn named(name: &str) -> Result<Option<String>, Error> {
+    let path = match user_config_path() {
+        Some(ref path) if path.exists() => path.clone(),
+        _ => return Ok(None),
+    };
+    let cfg: UserConfig = parse_config(&path,
+        &UserConfig::validator(), &Options::default())
+        .map_err(|e| ErrorKind::Config.error(e))?;
+    Ok(cfg.destinations.get(name).cloned())
+}
+
+fn find_local() -> Result<Option<PathBuf>, Error> {
+    let mut dir = env::current_dir()?;
+    loop {
+        for name in LOCAL_NAMES {
+            let path = dir.join(name);
+            if path.is_file() {
+                return Ok(Some(path));
+            }
+        }
+        if !dir.pop() {
+            return Ok(None);
+        }
+    }
+}
+
+/// Finds the destination config to use
+///
+/// The lookup order is:
+///
+/// 1. `--destination` if specified
+/// 2. `WARK_DESTINATION` environment variable
+/// 3. `.wark.yaml` or `wark.yaml` in current directory or any parent
+///
+/// A value of the first two may be a name of the destination defined in
+/// the `destinations` section of the user config
+/// (`~/.config/wark/config.yaml`), otherwise it's an url or a path.
+///
+/// When a local config is found in the parent directory, current directory
+/// is changed to that directory, so that all patterns in the config are
+/// resolved relative to the project root (like vagga does).
+pub fn find(explicit: &Option<String>) -> Result<String, Error> {
+    let value = match *explicit {
+        Some(ref value) => Some(value.clone()),
+        None => match env::var("WARK_DESTINATION") {
+            Ok(ref value) if value.len() > 0 => Some(value.clone()),
+            _ => None,
+        },
+    };
+    if let Some(value) = value {
+        if let Some(url) = named(&value)? {
+            debug!("Destination {:?} is {:?}", value, url);
+            return Ok(url);
+        }
+        return Ok(value);
+    }
+    match find_local()? {
+        Some(path) => {
+            let dir = path.parent().expect("config has a parent dir");
+            if dir != env::current_dir()? {
+                info!("Using {:?}", path);
+                env::set_current_dir(dir)?;
+            }
+            Ok(path.to_str().expect("path is utf-8").to_string())
+        }
+        None => {
+            Err(ErrorKind::Config.error(format_args!(
+                "no destination config found, use --destination, \
+                WARK_DESTINATION or create one of {:?}", LOCAL_NAMES)))
+        }
+    }
+}
@@ -2,7 +2,9 @@ use std::str::from_utf8;
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
 
-use failure::{Error, Context, ResultExt, err_msg};
+use failure::{Error, Context, ResultExt};
+
+use exit::{ErrorKind, ResultKind};
 
 
 pub fn download(url: &str, refresh: bool) -> Result<PathBuf, Error> {
@@ -20,12 +22,14 @@ pub fn download(url: &str, refresh: bool) -> Result<PathBuf, Error> {
         .with_context(|e| {
             error!("Error downloading {:?}: {}", url, e);
             Context::new("failed to run `vagga _capsule download`")
-        })?;
+        }).kind(ErrorKind::Network)?;
     if !res.status.success() {
         error!("Error executing `vagga _capsule download`: {}", res.status);
-        return Err(err_msg("failed to run `vagga _capsule download`"))
+        return Err(ErrorKind::Network
+            .error("failed to run `vagga _capsule download`"))
     }
     let path = from_utf8(&res.stdout)
-        .context("vagga returned invalid path")?.trim();
+        .context("vagga returned invalid path")
+        .kind(ErrorKind::Network)?.trim();
     Ok(PathBuf::from(path))
 }
@@ -1,12 +1,165 @@
 use std::fmt;
 use std::process::exit;
 
+use failure::{Error, Fail};
+use trimmer::{Variable, Output, DataError};
+
+
+/// Exit code when some stages are done before the deploy failed
+pub const PARTIAL_DEPLOY: i32 = 10;
+
+
+/// Kind of the error, determines exit code of the process when nothing
+/// is deployed yet (see `PARTIAL_DEPLOY` otherwise)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all="snake_case")]
+pub enum ErrorKind {
+    /// Destination config or vagga config can't be read or is invalid
+    Config,
+    /// Command-line arguments, variables or deploy state are invalid
+    Validation,
+    /// Container failed to build
+    Build,
+    /// Deployment is locked by somebody else or lock can't be acquired
+    Lock,
+    /// Ciruela failed to upload containers
+    Upload,
+    /// Verwalter rejected the deployment
+    Verwalter,
+    /// Network error when running the script
+    Network,
+    /// Command of the `command` stage failed
+    Command,
+}
+
+/// An error having a kind, see `ErrorKind::of`
+///
+/// Either has its own message, or displays the wrapped error.
+#[derive(Debug)]
+pub struct KindError {
+    kind: ErrorKind,
+    message: Option<String>,
+    cause: Option<Error>,
+}
+
+/// Adds kind to errors which don't have one yet
+pub trait ResultKind<T> {
+    fn kind(self, kind: ErrorKind) -> Result<T, Error>;
+}
 
 pub struct ExitCode {
     value: i32,
     closed: bool,
 }
 
+
+impl ErrorKind {
+    pub fn exit_code(&self) -> i32 {
+        use self::ErrorKind::*;
+        match *self {
+            Config => 2,
+            Validation => 3,
+            Build => 4,
+            Lock => 5,
+            Upload => 6,
+            Verwalter => 7,
+            Network => 8,
+            Command => 9,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        use self::ErrorKind::*;
+        match *self {
+            Config => "config",
+            Validation => "validation",
+            Build => "build",
+            Lock => "lock",
+            Upload => "upload",
+            Verwalter => "verwalter",
+            Network => "network",
+            Command => "command",
+        }
+    }
+    /// Creates an error of this kind
+    pub fn error<D: fmt::Display>(self, message: D) -> Error {
+        KindError {
+            kind: self,
+            message: Some(message.to_string()),
+            cause: None,
+        }.into()
+    }
+    /// Marks the error as being of this kind, keeping it as the cause
+    pub fn wrap(self, cause: Error) -> Error {
+        KindError { kind: self, message: None, cause: Some(cause) }.into()
+    }
+    /// Creates an error of this kind with a message and a cause
+    pub fn context<D: fmt::Display>(self, message: D, cause: Error)
+        -> Error
+    {
+        KindError {
+            kind: self,
+            message: Some(message.to_string()),
+            cause: Some(cause),
+        }.into()
+    }
+    /// Returns kind of the error or of any of its causes, `None` for
+    /// errors without a kind
+    pub fn of(err: &Error) -> Option<ErrorKind> {
+        let mut cur = Some(err.cause());
+        while let Some(fail) = cur {
+            if let Some(e) = fail.downcast_ref::<KindError>() {
+                return Some(e.kind);
+            }
+            cur = fail.cause();
+        }
+        None
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.name().fmt(f)
+    }
+}
+
+impl<'render> Variable<'render> for ErrorKind {
+    fn typename(&self) -> &'static str {
+        "ErrorKind"
+    }
+    fn output(&self) -> Result<Output, DataError> {
+        Ok(self.into())
+    }
+}
+
+impl fmt::Display for KindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.message, &self.cause) {
+            (&Some(ref message), _) => message.fmt(f),
+            (&None, &Some(ref cause)) => cause.fmt(f),
+            (&None, &None) => self.kind.fmt(f),
+        }
+    }
+}
+
+impl Fail for KindError {
+    fn cause(&self) -> Option<&Fail> {
+        self.cause.as_ref().map(|e| e.cause())
+    }
+}
+
+impl<T, E: Into<Error>> ResultKind<T> for Result<T, E> {
+    fn kind(self, kind: ErrorKind) -> Result<T, Error> {
+        self.map_err(|e| {
+            let e = e.into();
+            if ErrorKind::of(&e).is_some() {
+                e
+            } else {
+                kind.wrap(e)
+            }
+        })
+    }
+}
+
 impl ExitCode {
     pub fn new() -> ExitCode {
         ExitCode {
@@ -15,14 +168,26 @@ impl ExitCode {
         }
     }
     pub fn report_error(&mut self) {
-        self.value = 1;
+        if self.value == 0 {
+            self.value = 1;
+        }
+    }
+    /// Sets exit code for the error kind, unless set by previous error
+    pub fn report(&mut self, kind: ErrorKind) {
+        if self.value <= 1 {
+            self.value = kind.exit_code();
+        }
+    }
+    /// Sets exit code meaning that deploy failed after doing something
+    pub fn report_partial(&mut self) {
+        self.value = PARTIAL_DEPLOY;
     }
     pub fn is_ok(&self) -> bool {
         self.value == 0
     }
     pub fn error<D: fmt::Display>(&mut self, v: D) {
         error!("{}", v);
-        self.value = 1;
+        self.report_error();
     }
     pub fn fatal_context<A, B>(&mut self, a: A, v: B) -> !
         where A: fmt::Display, B: fmt::Display,
@@ -57,3 +222,62 @@ impl Drop for ExitCode {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::{ErrorKind, ExitCode, ResultKind, PARTIAL_DEPLOY};
+    use super::ErrorKind::*;
+
+    const ALL: &[ErrorKind] = &[
+        Config, Validation, Build, Lock, Upload, Verwalter, Network, Command,
+    ];
+
+    #[test]
+    fn exit_codes() {
+        let codes = ALL.iter().map(|k| k.exit_code()).collect::<Vec<_>>();
+        assert_eq!(codes, vec![2, 3, 4, 5, 6, 7, 8, 9]);
+        assert!(!codes.contains(&PARTIAL_DEPLOY));
+        let names = ALL.iter().map(|k| k.name()).collect::<BTreeSet<_>>();
+        assert_eq!(names.len(), ALL.len());
+        assert_eq!(Verwalter.to_string(), "verwalter");
+    }
+
+    #[test]
+    fn kind_of() {
+        assert_eq!(ErrorKind::of(&format_err!("plain")), None);
+        assert_eq!(ErrorKind::of(&Lock.error("locked")), Some(Lock));
+        let wrapped = Network.wrap(format_err!("timeout"));
+        assert_eq!(ErrorKind::of(&wrapped), Some(Network));
+        assert_eq!(wrapped.to_string(), "timeout");
+        let nested = Verwalter.context("rejected", Network.error("timeout"));
+        assert_eq!(ErrorKind::of(&nested), Some(Verwalter));
+        assert_eq!(nested.to_string(), "rejected");
+    }
+
+    #[test]
+    fn kind_is_kept() {
+        let res: Result<(), _> = Err(Upload.error("failed"));
+        assert_eq!(ErrorKind::of(&res.kind(Command).unwrap_err()),
+                   Some(Upload));
+        let res: Result<(), _> = Err(format_err!("failed"));
+        assert_eq!(ErrorKind::of(&res.kind(Command).unwrap_err()),
+                   Some(Command));
+    }
+
+    #[test]
+    fn first_kind_wins() {
+        let mut code = ExitCode::new();
+        assert!(code.is_ok());
+        code.report_error();
+        code.report(Build);
+        code.report(Lock);
+        code.report_error();
+        assert_eq!(code.value, 4);
+        code.report_partial();
+        code.report(Build);
+        assert_eq!(code.value, PARTIAL_DEPLOY);
+        code.closed = true;
+    }
+}
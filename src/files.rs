@@ -0,0 +1,81 @@
+use std::fs::{File, create_dir_all, rename};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use failure::Error;
+use serde::Serialize;
+use serde_json::to_writer_pretty;
+
+
+/// Replaces file, so that readers never see it partially written
+///
+/// `write` creates a temporary file next to `path` (it gets the name of
+/// the temporary file), which is then renamed over the `path`. Parent
+/// directory is created if it doesn't exist.
+pub fn replace<P, F>(path: P, write: F) -> Result<(), Error>
+    where P: AsRef<Path>,
+          F: FnOnce(&Path) -> Result<(), Error>,
+{
+    let path = path.as_ref();
+    if let Some(dir) = path.parent() {
+        if !dir.as_os_str().is_empty() && !dir.is_dir() {
+            create_dir_all(dir)
+                .map_err(|e| format_err!("can't create {:?}: {}", dir, e))?;
+        }
+    }
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_name = PathBuf::from(tmp_name);
+    write(&tmp_name)
+        .map_err(|e| format_err!("can't write {:?}: {}", tmp_name, e))?;
+    rename(&tmp_name, path)
+        .map_err(|e| format_err!("can't rename {:?}: {}", tmp_name, e))?;
+    Ok(())
+}
+
+/// Replaces file with the data
+pub fn write_bytes<P: AsRef<Path>>(path: P, data: &[u8])
+    -> Result<(), Error>
+{
+    replace(path, |tmp| {
+        File::create(tmp)?.write_all(data)?;
+        Ok(())
+    })
+}
+
+/// Replaces file with pretty-printed JSON of the value
+pub fn write_json<P: AsRef<Path>, T: Serialize>(path: P, value: &T)
+    -> Result<(), Error>
+{
+    replace(path, |tmp| {
+        to_writer_pretty(File::create(tmp)?, value)?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{File, remove_dir_all};
+    use std::io::Read;
+    use std::process;
+
+    use super::{write_bytes, replace};
+
+    #[test]
+    fn replace_file() {
+        let dir = env::temp_dir()
+            .join(format!("wark-files-{}", process::id()));
+        let path = dir.join("sub/file.txt");
+        write_bytes(&path, b"one").unwrap();
+        write_bytes(&path, b"two").unwrap();
+        let mut buf = String::new();
+        File::open(&path).unwrap().read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "two");
+        assert!(!dir.join("sub/file.txt.tmp").exists());
+
+        let err = replace(&path, |_| Err(format_err!("failed"))).unwrap_err();
+        assert!(err.to_string().contains("file.txt.tmp"));
+        remove_dir_all(&dir).unwrap();
+    }
+}
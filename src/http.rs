@@ -0,0 +1,64 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use failure::Error;
+use futures::{Future, Stream, Sink};
+use futures::future::{Either, ok, err, empty};
+use futures::stream::once;
+use futures::sync::oneshot;
+use ns_env_config::Router;
+use tk_easyloop::handle;
+use tk_http::client::{Codec, Config, Proto, Error as HError};
+use tokio_core::net::TcpStream;
+
+use exit::ErrorKind;
+
+
+/// Resolves the name and picks one of its addresses
+pub fn resolve(ns: &Router, host: &str, default_port: u16)
+    -> Box<Future<Item=SocketAddr, Error=Error>>
+{
+    let name = host.to_string();
+    Box::new(ns.resolve_auto(host, default_port)
+        .map_err(|e| ErrorKind::Network.error(e))
+        .and_then(move |addr| {
+            addr.pick_one().ok_or_else(|| {
+                ErrorKind::Network.error(format_args!(
+                    "could not resolve name {:?}", name))
+            })
+        }))
+}
+
+/// Sends a single request on a new connection to `addr`
+///
+/// The `codec` is expected to send the response to the other end of `rx`,
+/// which is what the returned future resolves to.
+pub fn request<C, T>(addr: SocketAddr, codec: C, rx: oneshot::Receiver<T>)
+    -> Box<Future<Item=T, Error=Error>>
+    where C: Codec<TcpStream> + 'static,
+          T: 'static,
+{
+    Box::new(TcpStream::connect(&addr, &handle())
+    .map_err(move |e| {
+        ErrorKind::Network.error(format_args!(
+            "error connecting to {}: {}", addr, e))
+    })
+    .and_then(move |sock| {
+        let proto = Proto::new(sock, &handle(), &Arc::new(Config::new()));
+        proto.send_all(once::<_, HError>(Ok(codec))
+            .chain(empty().into_stream()))
+        .select2(rx)
+        .then(move |res| match res {
+            Ok(Either::B((val, _))) => Either::A(ok(val)),
+            // connection may be closed right after the response
+            Err(Either::A((e, rx))) => {
+                Either::B(rx.map_err(move |_| e.into()))
+            }
+            Err(Either::B((e, _))) => Either::A(err(e.into())),
+            Ok(Either::A(_)) => {
+                Either::A(err(format_err!(
+                    "connection to {} closed without response", addr)))
+            }
+        })
+    }))
+}
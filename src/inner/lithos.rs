@@ -13,7 +13,7 @@ use lithos_shim::{ContainerConfig};
 use quire::{parse_config, Options as Quire};
 
 
-fn nice_cmdline(cfg: &ContainerConfig) -> String {
+pub(crate) fn nice_cmdline(cfg: &ContainerConfig) -> String {
     let mut res = String::with_capacity(100);
     let name_start = cfg.executable.rfind('/').map(|x| x+1).unwrap_or(0);
     let name = &cfg.executable[name_start..];
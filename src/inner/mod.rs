@@ -2,6 +2,7 @@ mod options;
 mod lithos;
 
 pub use self::options::Options;
+pub(crate) use self::lithos::nice_cmdline;
 
 use exit::ExitCode;
 
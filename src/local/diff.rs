@@ -0,0 +1,254 @@
+use std::fmt::Write;
+
+
+const CONTEXT: usize = 3;
+
+const BOLD: &str = "\x1b[1m";
+const CYAN: &str = "\x1b[36m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Same(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Splits text into lines, keeping line endings
+fn lines(text: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let end = rest.find('\n').map(|x| x+1).unwrap_or(rest.len());
+        result.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    return result;
+}
+
+/// Longest common subsequence of lines, common prefix and suffix are
+/// skipped before building a table, so it's quite fast for small changes
+fn edit_script(old: &[&str], new: &[&str]) -> Vec<Op> {
+    let prefix = old.iter().zip(new).take_while(|&(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b).count();
+    let a = &old[prefix..old.len()-suffix];
+    let b = &new[prefix..new.len()-suffix];
+    let width = b.len() + 1;
+    let mut table = vec![0usize; (a.len() + 1) * width];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            table[i*width + j] = if a[i] == b[j] {
+                table[(i+1)*width + j+1] + 1
+            } else {
+                table[(i+1)*width + j].max(table[i*width + j+1])
+            };
+        }
+    }
+    let mut ops: Vec<_> = (0..prefix).map(|i| Op::Same(i, i)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            ops.push(Op::Same(prefix+i, prefix+j));
+            i += 1;
+            j += 1;
+        } else if j == b.len() ||
+            i < a.len() && table[(i+1)*width + j] >= table[i*width + j+1]
+        {
+            ops.push(Op::Delete(prefix+i));
+            i += 1;
+        } else {
+            ops.push(Op::Insert(prefix+j));
+            j += 1;
+        }
+    }
+    for k in 0..suffix {
+        ops.push(Op::Same(old.len()-suffix+k, new.len()-suffix+k));
+    }
+    return ops;
+}
+
+fn line(buf: &mut String, prefix: char, text: &str, color: Option<&str>) {
+    if let Some(color) = color {
+        buf.push_str(color);
+    }
+    buf.push(prefix);
+    buf.push_str(text.trim_right_matches('\n'));
+    if color.is_some() {
+        buf.push_str(RESET);
+    }
+    buf.push('\n');
+    if !text.ends_with('\n') {
+        buf.push_str("\\ No newline at end of file\n");
+    }
+}
+
+/// Returns unified diff between old and new contents of the file
+///
+/// Output has git-style headers so it can be applied by `git apply`,
+/// `old` of `None` means that file doesn't exist yet. Returns empty string
+/// if there is no difference.
+pub fn unified(path: &str, old: Option<&str>, new: &str, color: bool)
+    -> String
+{
+    let old_lines = lines(old.unwrap_or(""));
+    let new_lines = lines(new);
+    let ops = edit_script(&old_lines, &new_lines);
+    let changes = ops.iter().enumerate()
+        .filter(|&(_, op)| !matches!(*op, Op::Same(..)))
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    if changes.is_empty() && old.is_some() {
+        return String::new();
+    }
+    let paint = |c: &'static str| if color { Some(c) } else { None };
+    let (bold, cyan, reset) = if color {
+        (BOLD, CYAN, RESET)
+    } else {
+        ("", "", "")
+    };
+
+    let mut buf = String::new();
+    writeln!(buf, "{}diff --git a/{} b/{}", bold, path, path).unwrap();
+    if old.is_none() {
+        writeln!(buf, "new file mode 100644").unwrap();
+        writeln!(buf, "--- /dev/null").unwrap();
+    } else {
+        writeln!(buf, "--- a/{}", path).unwrap();
+    }
+    writeln!(buf, "+++ b/{}{}", path, reset).unwrap();
+
+    let mut idx = 0;
+    while idx < changes.len() {
+        // group changes which have overlapping context into a single hunk
+        let mut last = idx;
+        while last + 1 < changes.len() &&
+            changes[last+1] - changes[last] <= 2*CONTEXT + 1
+        {
+            last += 1;
+        }
+        let start = changes[idx].saturating_sub(CONTEXT);
+        let end = (changes[last] + CONTEXT + 1).min(ops.len());
+        let hunk = &ops[start..end];
+        let (mut old_start, mut old_count) = (None, 0);
+        let (mut new_start, mut new_count) = (None, 0);
+        for op in hunk {
+            match *op {
+                Op::Same(i, j) => {
+                    old_start = old_start.or(Some(i));
+                    new_start = new_start.or(Some(j));
+                    old_count += 1;
+                    new_count += 1;
+                }
+                Op::Delete(i) => {
+                    old_start = old_start.or(Some(i));
+                    old_count += 1;
+                }
+                Op::Insert(j) => {
+                    new_start = new_start.or(Some(j));
+                    new_count += 1;
+                }
+            }
+        }
+        // empty range is denoted by the line before it
+        let old_start = old_start.map(|x| x+1).unwrap_or_else(|| {
+            ops[..start].iter()
+                .filter(|op| !matches!(**op, Op::Insert(..))).count()
+        });
+        let new_start = new_start.map(|x| x+1).unwrap_or_else(|| {
+            ops[..start].iter()
+                .filter(|op| !matches!(**op, Op::Delete(..))).count()
+        });
+        writeln!(buf, "{}@@ -{},{} +{},{} @@{}", cyan,
+            old_start, old_count, new_start, new_count, reset).unwrap();
+        for op in hunk {
+            match *op {
+                Op::Same(i, _) => line(&mut buf, ' ', old_lines[i], None),
+                Op::Delete(i) => {
+                    line(&mut buf, '-', old_lines[i], paint(RED))
+                }
+                Op::Insert(j) => {
+                    line(&mut buf, '+', new_lines[j], paint(GREEN))
+                }
+            }
+        }
+        idx = last + 1;
+    }
+    return buf;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Op, lines, edit_script, unified};
+
+    #[test]
+    fn split_lines() {
+        assert_eq!(lines(""), Vec::<&str>::new());
+        assert_eq!(lines("a\nb"), vec!["a\n", "b"]);
+        assert_eq!(lines("a\n\n"), vec!["a\n", "\n"]);
+    }
+
+    #[test]
+    fn script() {
+        use self::Op::*;
+        let old = lines("a\nb\nc\nd\n");
+        let new = lines("a\nc\nx\nd\n");
+        assert_eq!(edit_script(&old, &new), vec![
+            Same(0, 0), Delete(1), Same(2, 1), Insert(2), Same(3, 3),
+        ]);
+        assert_eq!(edit_script(&old, &old), vec![
+            Same(0, 0), Same(1, 1), Same(2, 2), Same(3, 3),
+        ]);
+        assert_eq!(edit_script(&[], &new[..1]), vec![Insert(0)]);
+        assert_eq!(edit_script(&old[..1], &[]), vec![Delete(0)]);
+    }
+
+    #[test]
+    fn same() {
+        assert_eq!(unified("f", Some("a\nb\n"), "a\nb\n", false), "");
+    }
+
+    #[test]
+    fn change() {
+        assert_eq!(unified("f", Some("1\n2\n3\n4\n5\n6\n7\n8\n9\n"),
+                           "1\n2\n3\n4\nfive\n6\n7\n8\n9\n", false), "\
+            diff --git a/f b/f\n\
+            --- a/f\n\
+            +++ b/f\n\
+            @@ -2,7 +2,7 @@\n \
+             2\n \
+             3\n \
+             4\n\
+            -5\n\
+            +five\n \
+             6\n \
+             7\n \
+             8\n");
+    }
+
+    #[test]
+    fn separate_hunks() {
+        let old = (1..21).map(|x| format!("{}\n", x)).collect::<String>();
+        let new = old.replace("\n2\n", "\ntwo\n")
+            .replace("\n19\n", "\nnineteen\n");
+        let diff = unified("f", Some(&old), &new, false);
+        let hunks = diff.lines().filter(|x| x.starts_with("@@"))
+            .collect::<Vec<_>>();
+        assert_eq!(hunks, vec!["@@ -1,5 +1,5 @@", "@@ -16,5 +16,5 @@"]);
+    }
+
+    #[test]
+    fn new_file() {
+        assert_eq!(unified("f", None, "a\nb", false), "\
+            diff --git a/f b/f\n\
+            new file mode 100644\n\
+            --- /dev/null\n\
+            +++ b/f\n\
+            @@ -0,0 +1,2 @@\n\
+            +a\n\
+            +b\n\
+            \\ No newline at end of file\n");
+    }
+}
@@ -1,28 +1,51 @@
-use std::io::{self, Read, Write};
-use std::fs::{File, rename, create_dir_all};
-use std::path::Path;
+use std::env;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Read};
+use std::fs::{File, Permissions, copy};
+use std::fs::{set_permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, Component};
+use std::str::FromStr;
 
-use difference::Changeset;
-use trimmer::{Context, RenderError};
+use atty::{self, Stream};
+use trimmer::{Context};
 use void::ResultVoidExt;
 
-use deploy::{Config, Spec, parse_spec_or_exit};
+use deploy::{Config, Spec, parse_spec, parse_spec_or_exit, preflight};
 use failure::Error;
 use exit::ExitCode;
+use files::{replace, write_bytes};
 use templates;
 
+mod diff;
+pub mod vagga;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Patch,
+}
+
 #[derive(Debug, Default, StructOpt)]
 pub struct CheckOptions {
+    #[structopt(help="how to print the difference: `text` (colored if \
+                      stdout is a terminal) or `patch` (suitable for \
+                      `git apply`)",
+        long="format", name="FORMAT", default_value="text",
+        raw(possible_values=r#"&["text", "patch"]"#))]
+    pub format: Format,
 }
 
 #[derive(Debug, Default, StructOpt)]
 pub struct UpdateOptions {
 }
 
-pub fn check_config(spec: &Spec) -> Result<bool, Error> {
-    let deploy_config = render_deploy_config(&spec)
-        .map_err(|e| format_err!("{}", e))?;
-    let ref filename = spec.config.vagga_config;
+/// Checks vagga config containing containers of the deployment
+pub fn check_config(spec: &Spec, deployment: &str) -> Result<bool, Error> {
+    let filename = spec.vagga_config(deployment)?;
+    let containers = spec.vagga_configs()?.remove(&filename)
+        .unwrap_or_else(BTreeSet::new);
+    let deploy_config = render_deploy_config(&spec, &containers)?;
     let mut buf = String::with_capacity(1024);
     let mut f = match File::open(&filename) {
         Ok(f) => f,
@@ -31,24 +54,156 @@ pub fn check_config(spec: &Spec) -> Result<bool, Error> {
     };
     f.read_to_string(&mut buf)
         .map_err(|e| format_err!("Error reading {:?}: {}", filename, e))?;
-    return Ok(deploy_config == buf);
+    Ok(deploy_config == buf)
 }
 
-pub fn check(_options: CheckOptions, config: Config) -> ! {
+/// Checks that running binary is copied into the project if configured so
+pub fn check_binary(spec: &Spec) -> Result<bool, Error> {
+    let path = match spec.config.inner_wark.current_path() {
+        Some(path) => path,
+        None => return Ok(true),
+    };
+    let cur = env::current_exe()?;
+    let mut cur_data = Vec::new();
+    File::open(&cur)
+        .and_then(|mut f| f.read_to_end(&mut cur_data))
+        .map_err(|e| format_err!("Error reading {:?}: {}", cur, e))?;
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut f) => {
+            f.read_to_end(&mut data)
+            .map_err(|e| format_err!("Error reading {:?}: {}", path, e))?;
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => bail!("Can't open file {:?}: {}", path, e),
+    }
+    Ok(data == cur_data)
+}
+
+fn update_binary(spec: &Spec) -> Result<(), Error> {
+    let path = match spec.config.inner_wark.current_path() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    replace(path, |tmp| {
+        copy(env::current_exe()?, tmp)?;
+        set_permissions(tmp, Permissions::from_mode(0o755))?;
+        Ok(())
+    })
+}
+
+/// Checks that volume mount points of lithos configs are sane
+fn check_volumes(spec: &Spec, exit: &mut ExitCode) {
+    let configs = spec.deployments.values()
+        .flat_map(|d| {
+            d.daemons.values().map(|x| (&x.config_path, &x.config))
+            .chain(d.commands.values().map(|x| (&x.config_path, &x.config)))
+        })
+        .collect::<BTreeMap<_, _>>();
+    for (path, config) in configs {
+        for volume in config.volumes.keys() {
+            let vpath = Path::new(volume);
+            if !vpath.is_absolute() {
+                exit.error(format_args!(
+                    "{}: volume path {:?} must be absolute", path, volume));
+            } else if vpath.components()
+                .any(|c| c == Component::ParentDir || c == Component::CurDir)
+            {
+                exit.error(format_args!(
+                    "{}: volume path {:?} must be normalized", path, volume));
+            }
+        }
+    }
+}
+
+/// Checks that containers of lithos configs are defined in vagga config
+///
+/// The `defined` containers are the ones in project's `vagga.yaml`, close
+/// matches are suggested for misspelled names.
+pub fn check_containers(spec: &Spec, defined: &BTreeSet<String>,
+    exit: &mut ExitCode)
+{
+    let configs = spec.deployments.values()
+        .flat_map(|d| {
+            d.daemons.values().map(|x| (&x.config_path, &x.container))
+            .chain(d.commands.values()
+                .map(|x| (&x.config_path, &x.container)))
+        })
+        .collect::<BTreeMap<_, _>>();
+    for (path, container) in configs {
+        if defined.contains(container) {
+            continue;
+        }
+        let names = vagga::similar(container, defined);
+        let hint = if names.is_empty() {
+            String::new()
+        } else {
+            format!(", did you mean {}?", names.iter()
+                .map(|x| format!("{:?}", x))
+                .collect::<Vec<_>>().join(" or "))
+        };
+        exit.error(format_args!("{}: container {:?} is not defined in {}{}",
+            path, container, vagga::VAGGA_CONFIG, hint));
+    }
+}
+
+/// Runs all checks that can be done without building containers
+pub fn check(options: CheckOptions, config: Config,
+    vars: HashMap<String, String>, target: Option<String>)
+    -> !
+{
     let mut exit = ExitCode::new();
-    let spec = parse_spec_or_exit(config);
-    let deploy_config = render_deploy_config(&spec)
+    let spec = parse_spec(config, &mut exit);
+    preflight(&spec, &vars, &target, &mut exit);
+    check_volumes(&spec, &mut exit);
+    match vagga::containers(Path::new(vagga::VAGGA_CONFIG)) {
+        Ok(defined) => check_containers(&spec, &defined, &mut exit),
+        Err(e) => exit.error(e),
+    }
+
+    let color = options.format == Format::Text && atty::is(Stream::Stdout);
+    let configs = spec.vagga_configs()
         .map_err(|e| exit.fatal_error(e)).void_unwrap();
-    let ref filename = spec.config.vagga_config;
-    let mut buf = String::with_capacity(1024);
-    File::open(&filename)
-        .and_then(|mut f| f.read_to_string(&mut buf))
-        .map_err(|e| exit.fatal_context(filename, e)).void_unwrap();
-    if deploy_config != buf {
-        exit.report_error();
-        eprintln!("Config {:?} is not up to date", spec.config.vagga_config);
-        println!("{}", Changeset::new(&buf, &deploy_config, "\n"));
-        info!("To fix config run: vagga deploy update");
+    let mut outdated = false;
+    for (filename, containers) in &configs {
+        let deploy_config = render_deploy_config(&spec, containers)
+            .map_err(|e| exit.fatal_error(e)).void_unwrap();
+        let mut buf = String::with_capacity(1024);
+        let old = match File::open(&filename) {
+            Ok(mut f) => {
+                f.read_to_string(&mut buf)
+                .map_err(|e| exit.fatal_context(filename, e)).void_unwrap();
+                Some(&buf[..])
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                eprintln!("Config {:?} does not exist", filename);
+                None
+            }
+            Err(e) => exit.fatal_context(filename, e),
+        };
+        let patch = diff::unified(filename, old, &deploy_config, color);
+        if !patch.is_empty() {
+            exit.report_error();
+            outdated = true;
+            if old.is_some() {
+                eprintln!("Config {:?} is not up to date", filename);
+            }
+            print!("{}", patch);
+        }
+    }
+    if let Some(path) = spec.config.inner_wark.current_path() {
+        match check_binary(&spec) {
+            Ok(true) => {}
+            Ok(false) => {
+                exit.report_error();
+                outdated = true;
+                eprintln!("Wark binary {:?} is not up to date", path);
+            }
+            Err(e) => exit.error(e),
+        }
+    }
+    if outdated {
+        info!("To fix generated files run: vagga deploy update");
     }
     if exit.is_ok() {
         info!("Everything is fine, ready for deploy");
@@ -56,28 +211,59 @@ pub fn check(_options: CheckOptions, config: Config) -> ! {
     exit.exit();
 }
 
-fn render_deploy_config(spec: &Spec) -> Result<String, RenderError> {
+impl Default for Format {
+    fn default() -> Format {
+        Format::Text
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Format, Error> {
+        match s {
+            "text" => Ok(Format::Text),
+            "patch" => Ok(Format::Patch),
+            _ => bail!("unknown format {:?}", s),
+        }
+    }
+}
+
+fn render_deploy_config(spec: &Spec, containers: &BTreeSet<String>)
+    -> Result<String, Error>
+{
     let mut ctx = Context::new();
     ctx.set("spec", spec);
-    templates::VAGGA_DEPLOY_CONFIG.render(&ctx)
+    ctx.set("containers", containers);
+    match spec.config.vagga_template.0 {
+        Some(ref path) => {
+            let mut buf = String::with_capacity(1024);
+            File::open(path)
+                .and_then(|mut f| f.read_to_string(&mut buf))
+                .map_err(|e| format_err!("Error reading {:?}: {}", path, e))?;
+            let tpl = templates::PARSER.parse(&buf)
+                .map_err(|e| format_err!("Error parsing {:?}: {}", path, e))?;
+            tpl.render(&ctx)
+                .map_err(|e| format_err!("Error rendering {:?}: {}", path, e))
+        }
+        None => {
+            templates::VAGGA_DEPLOY_CONFIG.render(&ctx)
+                .map_err(|e| format_err!("{}", e))
+        }
+    }
 }
 
 pub fn update(_options: UpdateOptions, config: Config) -> ! {
     let mut exit = ExitCode::new();
     let spec = parse_spec_or_exit(config);
-    let deploy_config = render_deploy_config(&spec)
+    let configs = spec.vagga_configs()
         .map_err(|e| exit.fatal_error(e)).void_unwrap();
-    if let Some(dir) = Path::new(&spec.config.vagga_config).parent() {
-        if !dir.is_dir() {
-            create_dir_all(&dir)
+    for (filename, containers) in &configs {
+        let deploy_config = render_deploy_config(&spec, containers)
+            .map_err(|e| exit.fatal_error(e)).void_unwrap();
+        write_bytes(filename, deploy_config.as_bytes())
             .map_err(|e| exit.fatal_error(e)).void_unwrap();
-        }
     }
-    let tmp_name = format!("{}.tmp", spec.config.vagga_config);
-    File::create(&tmp_name)
-        .and_then(|mut f| f.write_all(deploy_config.as_bytes()))
-        .map_err(|e| exit.fatal_error(e)).void_unwrap();
-    rename(&tmp_name, spec.config.vagga_config)
+    update_binary(&spec)
         .map_err(|e| exit.fatal_error(e)).void_unwrap();
     if exit.is_ok() {
         info!("All done, ready for deploy");
@@ -0,0 +1,165 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::str::from_utf8;
+
+use failure::{Error, err_msg};
+use quire::ast::{self, Ast};
+use quire::{raw_parse, ErrorCollector, Options, Include, Pos};
+use quire::{Error as QuireError};
+
+
+/// Project's vagga config, the generated one refers to it in `!SubConfig`
+pub static VAGGA_CONFIG: &str = "vagga.yaml";
+
+
+/// Returns names of the containers defined in vagga config and its mixins
+///
+/// Only the names are read, so we don't need to know the whole schema of
+/// vagga config. Missing mixins are skipped as vagga does.
+pub fn containers(path: &Path) -> Result<BTreeSet<String>, Error> {
+    let mut result = BTreeSet::new();
+    let mut visited = Vec::new();
+    read_containers(path, &mut result, &mut visited)?;
+    Ok(result)
+}
+
+/// Returns defined names similar to the specified one, closest first
+pub fn similar<'x>(name: &str, defined: &'x BTreeSet<String>)
+    -> Vec<&'x str>
+{
+    let max = ::std::cmp::max(1, name.chars().count() / 3);
+    let mut result = defined.iter()
+        .map(|x| (distance(name, x), &x[..]))
+        .filter(|&(dist, _)| dist <= max)
+        .collect::<Vec<_>>();
+    result.sort();
+    result.into_iter().map(|(_, x)| x).collect()
+}
+
+/// Returns vagga's version hash of the container
+///
+/// This is the hash vagga uses to find out whether container needs to be
+/// rebuilt, so it's computed from the same inputs including the files
+/// container depends on, but doesn't require building the container.
+pub fn version_hash(container: &str) -> Result<String, Error> {
+    let output = Command::new("vagga")
+        .arg("_version_hash").arg(container)
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .output()
+        .map_err(|e| format_err!("can't run vagga: {}", e))?;
+    if !output.status.success() {
+        bail!("vagga _version_hash exited with {}", output.status);
+    }
+    match from_utf8(&output.stdout).map(|x| x.trim()) {
+        Ok(hash) if hash.len() > 0 => Ok(hash.to_string()),
+        _ => bail!("invalid version hash {:?}",
+            String::from_utf8_lossy(&output.stdout)),
+    }
+}
+
+/// Levenshtein distance
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..b.len()+1).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let cur = row[j+1];
+            row[j+1] = if ca == b[j] {
+                prev
+            } else {
+                1 + prev.min(cur).min(row[j])
+            };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+fn read_containers(path: &Path, result: &mut BTreeSet<String>,
+    visited: &mut Vec<PathBuf>)
+    -> Result<(), Error>
+{
+    if visited.iter().any(|x| x == path) {
+        return Ok(());
+    }
+    visited.push(path.to_path_buf());
+    let ast = parse(path)?;
+    let map = match ast {
+        Ast::Map(_, _, map) => map,
+        Ast::Null(..) => return Ok(()),
+        _ => bail!("{:?}: config must be a mapping", path),
+    };
+    match map.get("containers") {
+        Some(&Ast::Map(_, _, ref containers)) => {
+            result.extend(containers.keys().cloned());
+        }
+        Some(&Ast::Null(..)) | None => {}
+        Some(_) => bail!("{:?}: containers must be a mapping", path),
+    }
+    if let Some(&Ast::Seq(_, _, ref mixins)) = map.get("mixins") {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for item in mixins {
+            if let Ast::Scalar(_, _, _, ref name) = *item {
+                let mixin = dir.join(name);
+                if mixin.exists() {
+                    read_containers(&mixin, result, visited)?;
+                } else {
+                    debug!("Mixin {:?} not found", mixin);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    let mut buf = String::with_capacity(1024);
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut buf))
+        .map_err(|e| format!("can't read {:?}: {}", path, e))?;
+    Ok(buf)
+}
+
+fn include(pos: &Pos, incl: &Include, err: &ErrorCollector, opt: &Options)
+    -> Ast
+{
+    match *incl {
+        Include::File { filename } => {
+            let path = Path::new(&*pos.filename).parent()
+                .unwrap_or(Path::new(".")).join(filename);
+            let body = match read(&path) {
+                Ok(body) => body,
+                Err(e) => {
+                    err.add_error(QuireError::preprocess_error(pos, e));
+                    return Ast::void(pos);
+                }
+            };
+            let name = Rc::new(path.display().to_string());
+            raw_parse(name, &body, |doc| ast::process(opt, doc, err))
+                .unwrap_or_else(|e| {
+                    err.add_error(e);
+                    Ast::void(pos)
+                })
+        }
+    }
+}
+
+fn parse(path: &Path) -> Result<Ast, Error> {
+    let body = read(path).map_err(err_msg)?;
+    let err = ErrorCollector::new();
+    let mut options = Options::default();
+    options.allow_include(include);
+    let name = Rc::new(path.display().to_string());
+    let ast = raw_parse(name, &body, |doc| ast::process(&options, doc, &err))
+        .map_err(|e| err.into_fatal(e))
+        .and_then(|ast| err.into_result(ast))
+        .map_err(|e| err_msg(format!("{}", e)))?;
+    Ok(ast)
+}
@@ -1,15 +1,18 @@
+extern crate atty;
 extern crate capturing_glob;
-extern crate difference;
 extern crate env_logger;
 extern crate futures;
+extern crate libc;
 extern crate libflate;
 extern crate lithos_shim;
 extern crate ns_env_config;
 extern crate quire;
 extern crate rand;
+extern crate regex;
 extern crate semver;
 extern crate serde;
 extern crate serde_json;
+extern crate sha1;
 extern crate tar;
 extern crate tk_easyloop;
 extern crate tk_http;
@@ -21,41 +24,41 @@ extern crate void;
 #[macro_use] extern crate structopt;
 #[macro_use] extern crate log;
 #[macro_use] extern crate lazy_static;
+#[macro_use] extern crate matches;
 #[macro_use] extern crate serde_derive;
 #[macro_use] extern crate trimmer_derive;
 #[cfg(feature="git")] extern crate git2;
 
 
-use std::collections::HashMap;
-use std::process::exit;
 use structopt::StructOpt;
 
 mod base;
 mod deploy;
+mod destination;
 mod download;
 mod exit;
+mod files;
+mod http;
 mod inner;
 mod local;
 mod options;
+mod redact;
 mod templates;
 mod version;
 mod wark_version;
 
 use std::env;
+use std::time::Instant;
+
+use exit::ErrorKind;
 
 fn config(path: &Option<String>) -> deploy::Config {
-    let path = match *path {
-        Some(ref path) => path,
-        None => {
-            eprintln!("--destination is required");
-            exit(1);
-        }
-    };
-    download::download(path, true)
+    destination::find(path)
     .and_then(|path| deploy::Config::parse(&path))
     .unwrap_or_else(|e| {
         eprintln!("{}", e);
-        ::std::process::exit(1);
+        ::std::process::exit(ErrorKind::of(&e)
+            .unwrap_or(ErrorKind::Config).exit_code());
     })
 }
 
@@ -65,30 +68,46 @@ fn main() {
     if env::var("WARK_LOG").is_err() {
         env::set_var("WARK_LOG", "info");
     }
-    env_logger::init_from_env("WARK_LOG");
+    redact::init_logger("WARK_LOG");
 
     let opts = options::Options::from_args();
     let ref dest = opts.destination;
     match opts.command {
         Some(Inner(sub)) => inner::main(sub),
-        Some(Check(sub)) => local::check(sub, config(dest)),
+        Some(Check(sub)) => {
+            let vars = deploy::vars::parse_defines(&opts.var);
+            local::check(sub, config(dest), vars, opts.target)
+        }
         Some(Update(sub)) => local::update(sub, config(dest)),
-        None if opts.deployment.is_some() => {
-            let mut vars = HashMap::new();
-            for pair in &opts.var {
-                let mut iter = pair.splitn(2, '=');
-                match (iter.next(), iter.next()) {
-                    (Some(key), Some(val)) => {
-                        vars.insert(key.to_string(), val.to_string());
-                    }
-                    (Some(key), None) => {
-                        vars.insert(key.to_string(), "true".to_string());
-                    }
-                    _ => unreachable!(),
-                }
-            }
-            deploy::main(config(dest), opts.deployment.unwrap(),
-                opts.dry_run, vars)
+        Some(Vars(sub)) => base::vars(sub, config(dest)),
+        Some(Show(sub)) => base::show(sub, config(dest)),
+        None if opts.deployment.len() > 0 || opts.all => {
+            let vars = deploy::vars::parse_defines(&opts.var);
+            let filter = deploy::Filter {
+                only: opts.only,
+                exclude: opts.exclude,
+                stages: opts.stage,
+                skip_stages: opts.skip_stage,
+            };
+            let flags = deploy::Flags {
+                dry_run: opts.dry_run,
+                refresh: opts.refresh,
+                force_unlock: opts.force_unlock,
+                resume: opts.resume,
+                yes: opts.yes,
+                json: opts.json,
+            };
+            let deployments = if opts.all {
+                vec![String::from("*")]
+            } else {
+                opts.deployment
+            };
+            let mut timings = deploy::Timings::new();
+            let start = Instant::now();
+            let config = config(dest);
+            timings.add("config", start);
+            deploy::main(config, deployments,
+                vars, opts.target, filter, flags, timings)
         }
         None => base::main(config(dest)),
     }
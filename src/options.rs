@@ -1,20 +1,80 @@
+use base;
 use inner;
 use local;
 
 
 #[derive(Debug, Default, StructOpt)]
 pub struct Options {
-    #[structopt(help="a JSON file that represents deployment config",
+    #[structopt(help="a JSON file that represents deployment config, \
+                      or name of the destination from user config. \
+                      Defaults to `WARK_DESTINATION` or `.wark.yaml` \
+                      found in current or parent directory",
               long="--destination", name="URL")]
     pub destination: Option<String>,
 
-    #[structopt(help="a deployment name to deploy now",
+    #[structopt(help="a deployment name (or a glob like `eu-*`) to deploy \
+                      now, may be specified multiple times",
         name="NAME", short="d", long="deployment")]
-    pub deployment: Option<String>,
+    #[structopt(raw(number_of_values="1"))]
+    pub deployment: Vec<String>,
+
+    #[structopt(help="deploy all deployments", long="all")]
+    pub all: bool,
+
+    #[structopt(help="a target (environment) from the destination config",
+        name="TARGET", short="t", long="target")]
+    pub target: Option<String>,
+
+    #[structopt(help="deploy only specified daemon or command \
+                      of the deployment, other ones are kept intact",
+                name="PROCESS", long="only")]
+    #[structopt(raw(number_of_values="1"))]
+    pub only: Vec<String>,
+
+    #[structopt(help="don't deploy specified daemon or command \
+                      of the deployment, keep it intact",
+                name="EXCLUDED_PROCESS", long="exclude")]
+    #[structopt(raw(number_of_values="1"))]
+    pub exclude: Vec<String>,
+
+    #[structopt(help="run only specified stage of the script \
+                      (a name or a tool if stage has no name)",
+                name="STAGE", long="stage")]
+    #[structopt(raw(number_of_values="1"))]
+    pub stage: Vec<String>,
+
+    #[structopt(help="don't run specified stage of the script",
+                name="SKIPPED_STAGE", long="skip-stage")]
+    #[structopt(raw(number_of_values="1"))]
+    pub skip_stage: Vec<String>,
 
     #[structopt(help="prepare everything but don't deploy", long="dry-run")]
     pub dry_run: bool,
 
+    #[structopt(help="don't use cached container versions, \
+                      look them up again and update the cache",
+                long="refresh")]
+    pub refresh: bool,
+
+    #[structopt(help="release deploy lock held by somebody else \
+                      (make sure nobody is deploying right now)",
+                long="force-unlock")]
+    pub force_unlock: bool,
+
+    #[structopt(help="continue previous failed deploy from the first \
+                      failed stage, if version and containers are the same",
+                long="resume")]
+    pub resume: bool,
+
+    #[structopt(help="don't ask to confirm deploy of protected \
+                      deployments (required when not running in a terminal)",
+                short="y", long="yes")]
+    pub yes: bool,
+
+    #[structopt(help="print deploy report with results and timings \
+                      as JSON to stdout", long="json")]
+    pub json: bool,
+
     #[structopt(help="define variable (passed as `var.NAME` to templates)",
                 name="NAME=VALUE", short="D", long="var")]
     #[structopt(raw(number_of_values="1"))]
@@ -30,8 +90,15 @@ pub enum Command {
         about="Runs all preparation from inside a container")]
     Inner(inner::Options),
     #[structopt(name="check",
-        about="Checks that configs are up to date (run it in CI)")]
+        about="Checks configs and everything a deploy would check \
+              without building containers (run it in CI)")]
     Check(local::CheckOptions),
     #[structopt(name="update", about="Updates generated config files")]
     Update(local::UpdateOptions),
+    #[structopt(name="vars",
+        about="Lists variables declared in the destination config")]
+    Vars(base::VarsOptions),
+    #[structopt(name="show",
+        about="Shows fully resolved deployment")]
+    Show(base::ShowOptions),
 }
@@ -0,0 +1,82 @@
+use std::sync::RwLock;
+
+use env_logger::{self, Env};
+use log::{self, Log, Record, Metadata};
+
+
+lazy_static! {
+    static ref SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+}
+
+struct Logger(env_logger::Logger);
+
+
+/// Registers a value that must never be shown to the user
+pub fn add_secret(value: &str) {
+    if value.is_empty() {
+        return;
+    }
+    let mut secrets = SECRETS.write().expect("secrets lock");
+    if !secrets.iter().any(|x| x == value) {
+        secrets.push(value.to_string());
+        // longest first, so a secret containing another one is hidden fully
+        secrets.sort_by(|a, b| b.len().cmp(&a.len()));
+    }
+}
+
+/// Replaces every registered secret in the text with `***`
+pub fn redact(text: &str) -> String {
+    let secrets = SECRETS.read().expect("secrets lock");
+    let mut res = text.to_string();
+    for secret in secrets.iter() {
+        if res.contains(&secret[..]) {
+            res = res.replace(&secret[..], "***");
+        }
+    }
+    return res;
+}
+
+/// Initializes `env_logger` wrapped in a logger that redacts secrets
+pub fn init_logger<'a, E: Into<Env<'a>>>(env: E) {
+    let logger = env_logger::Builder::from_env(env).build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(Logger(logger)))
+        .expect("logger is initialized only once");
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+    fn log(&self, record: &Record) {
+        if !self.0.matches(record) {
+            return;
+        }
+        let text = redact(&record.args().to_string());
+        self.0.log(&Record::builder()
+            .args(format_args!("{}", text))
+            .metadata(record.metadata().clone())
+            .module_path(record.module_path())
+            .file(record.file())
+            .line(record.line())
+            .build());
+    }
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{add_secret, redact};
+
+    #[test]
+    fn longest_first() {
+        add_secret("redact-test-secret");
+        add_secret("redact-test-secret-long");
+        add_secret("");
+        assert_eq!(redact("a redact-test-secret-long b redact-test-secret"),
+                   "a *** b ***");
+        assert_eq!(redact("nothing here"), "nothing here");
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+
+use failure::Error;
+use serde::de::{self, Visitor, Deserializer, Deserialize};
+use trimmer::{Template, Output, DataError, Variable, Context};
+
+use templates::PARSER;
+
+
+/// A boolean expression, like `target == "prod" and vars.region`
+///
+/// Evaluated with the same rules as `## if` in templates, i.e. undefined
+/// values and empty strings are false.
+#[derive(Debug)]
+pub struct Condition {
+    text: String,
+    ast: Template,
+}
+
+struct ConditionVisitor;
+
+
+impl Condition {
+    /// Evaluates condition, error refers to the config key it's at
+    pub fn check_key(&self, key: &str, ctx: &Context) -> Result<bool, Error> {
+        self.ast.render(ctx)
+            .map(|x| x.trim() == "true")
+            .map_err(|e| format_err!("can't evaluate {} ({:?}): {}",
+                key, self.text, e))
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.text.fmt(f)
+    }
+}
+
+impl<'de> Visitor<'de> for ConditionVisitor {
+    type Value = Condition;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a valid trimmer expression")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where E: de::Error
+    {
+        if v.contains('\n') {
+            return Err(E::custom("condition must be a single line"));
+        }
+        let text = format!("## if {}\ntrue\n## endif\n", v);
+        match PARSER.parse(&text) {
+            Ok(tpl) => {
+                Ok(Condition {
+                    text: v.to_string(),
+                    ast: tpl,
+                })
+            }
+            Err(e) => {
+                Err(E::custom(e))
+            }
+        }
+    }
+}
+
+impl<'a> Deserialize<'a> for Condition {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'a>
+    {
+        deserializer.deserialize_str(ConditionVisitor)
+    }
+}
+
+impl<'render> Variable<'render> for Condition {
+    fn typename(&self) -> &'static str {
+        "Condition"
+    }
+
+    fn output(&self) -> Result<Output, DataError> {
+        Ok((&self.text).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use serde_json::from_str;
+    use serde_json::to_string;
+    use trimmer::Context;
+
+    use templates::Value;
+    use super::Condition;
+
+    fn parse(text: &str) -> Result<Condition, String> {
+        from_str(&to_string(text).unwrap()).map_err(|e| e.to_string())
+    }
+
+    fn check(text: &str, target: Option<&str>, region: &str) -> bool {
+        let mut vars = HashMap::new();
+        vars.insert("region".to_string(), Value(region.to_string()));
+        let (yes, no) = (true, false);
+        let mut ctx = Context::new();
+        ctx.set("target", &target);
+        ctx.set("vars", &vars);
+        ctx.set("true", &yes);
+        ctx.set("false", &no);
+        parse(text).unwrap().check_key("when", &ctx).unwrap()
+    }
+
+    #[test]
+    fn compare() {
+        assert!(check(r#"target == "prod""#, Some("prod"), ""));
+        assert!(!check(r#"target == "prod""#, Some("dev"), ""));
+        assert!(!check(r#"target == "prod""#, None, ""));
+        assert!(check(r#"target != "prod""#, None, ""));
+    }
+
+    #[test]
+    fn logic() {
+        let cond = r#"target == "prod" and vars.region"#;
+        assert!(check(cond, Some("prod"), "eu"));
+        assert!(!check(cond, Some("prod"), ""));
+        assert!(!check(cond, Some("dev"), "eu"));
+        assert!(check(r#"target == "dev" or vars.region"#, None, "eu"));
+        assert!(check("not vars.region", None, ""));
+    }
+
+    #[test]
+    fn constants() {
+        assert!(check("true", None, ""));
+        assert!(!check("false", None, ""));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(parse("vars.region").unwrap().to_string(), "vars.region");
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse("a\nb").is_err());
+        assert!(parse("target ==").is_err());
+    }
+
+    #[test]
+    fn unknown_variable() {
+        let ctx = Context::new();
+        let err = parse("vars.region").unwrap().check_key("x.when", &ctx)
+            .unwrap_err();
+        assert!(err.to_string().starts_with("can't evaluate x.when"));
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt;
+
+use serde::de::{self, Visitor, Deserializer, Deserialize};
+use trimmer::{Variable, Output, DataError};
+
+
+/// A glob pattern with capture groups, which may be named
+///
+/// Named groups look like `(?P<name>*)`, the name is stripped before
+/// passing the pattern to glob, so it's equivalent to `(*)`. Both named
+/// and unnamed groups are accessible by (1-based) index.
+#[derive(Debug)]
+pub struct GlobPattern {
+    text: String,
+    glob: String,
+    names: Vec<Option<String>>,
+}
+
+struct GlobPatternVisitor;
+
+
+fn parse(text: &str) -> Result<GlobPattern, String> {
+    let mut glob = String::with_capacity(text.len());
+    let mut names: Vec<Option<String>> = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        glob.push(c);
+        match c {
+            '[' => {
+                // char class may contain parenthesis, copy it as is
+                while let Some(c) = chars.next() {
+                    glob.push(c);
+                    if c == ']' {
+                        break;
+                    }
+                }
+            }
+            '(' if chars.peek() == Some(&'?') => {
+                let prefix: String = chars.by_ref().take(3).collect();
+                if prefix != "?P<" {
+                    return Err(format!("group must either be unnamed `(*)` \
+                        or named `(?P<name>*)`, got {:?}", prefix));
+                }
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('>') => break,
+                        Some(c) => name.push(c),
+                        None => {
+                            return Err(format!("unterminated group name {:?}",
+                                name));
+                        }
+                    }
+                }
+                if name.is_empty() ||
+                    !name.chars().all(|c| c.is_alphanumeric() || c == '_') ||
+                    name.chars().next().map(|c| c.is_digit(10)) == Some(true)
+                {
+                    return Err(format!("invalid group name {:?}", name));
+                }
+                if names.iter().any(|x| x.as_ref() == Some(&name)) {
+                    return Err(format!("duplicate group name {:?}", name));
+                }
+                names.push(Some(name));
+            }
+            '(' => names.push(None),
+            _ => {}
+        }
+    }
+    Ok(GlobPattern {
+        text: text.to_string(),
+        glob, names,
+    })
+}
+
+impl GlobPattern {
+    /// Pattern with group names stripped, suitable for `capturing_glob`
+    pub fn glob(&self) -> &str {
+        &self.glob
+    }
+    /// Number of capture groups
+    pub fn groups(&self) -> usize {
+        self.names.len()
+    }
+    /// Returns 1-based index of a named group
+    pub fn group_index(&self, name: &str) -> Option<usize> {
+        self.names.iter()
+            .position(|x| x.as_ref().map(|x| &x[..]) == Some(name))
+            .map(|x| x+1)
+    }
+}
+
+impl fmt::Display for GlobPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.text.fmt(f)
+    }
+}
+
+impl<'de> Visitor<'de> for GlobPatternVisitor {
+    type Value = GlobPattern;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a glob pattern with capture groups")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where E: de::Error
+    {
+        parse(v).map_err(E::custom)
+    }
+}
+
+impl<'a> Deserialize<'a> for GlobPattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'a>
+    {
+        deserializer.deserialize_str(GlobPatternVisitor)
+    }
+}
+
+impl<'render> Variable<'render> for GlobPattern {
+    fn typename(&self) -> &'static str {
+        "GlobPattern"
+    }
+
+    fn output(&self) -> Result<Output, DataError> {
+        Ok((&self.text).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse;
+
+    #[test]
+    fn named_groups() {
+        let p = parse("config/(?P<region>*)-(*)/(?P<role>*).yaml").unwrap();
+        assert_eq!(p.glob(), "config/(*)-(*)/(*).yaml");
+        assert_eq!(p.groups(), 3);
+        assert_eq!(p.group_index("region"), Some(1));
+        assert_eq!(p.group_index("role"), Some(3));
+        assert_eq!(p.group_index("other"), None);
+        assert_eq!(p.to_string(), "config/(?P<region>*)-(*)/(?P<role>*).yaml");
+    }
+
+    #[test]
+    fn char_class() {
+        let p = parse("[(]x(?P<name>*)").unwrap();
+        assert_eq!(p.glob(), "[(]x(*)");
+        assert_eq!(p.groups(), 1);
+        assert_eq!(p.group_index("name"), Some(1));
+    }
+
+    #[test]
+    fn bad_groups() {
+        assert!(parse("(?:*)").is_err());
+        assert!(parse("(?P<>*)").is_err());
+        assert!(parse("(?P<1x>*)").is_err());
+        assert!(parse("(?P<a-b>*)").is_err());
+        assert!(parse("(?P<name*)").is_err());
+        assert!(parse("(?P<x>*)/(?P<x>*)").is_err());
+    }
+}
@@ -1,30 +1,45 @@
 use trimmer::{Variable, Output, Var, DataError};
 use capturing_glob::Entry;
 
+use templates::{Value, GlobPattern};
+
+
+static EXAMPLE: &str = "a";
+
+
 #[derive(Debug)]
-pub struct GlobVar {
+pub struct GlobVar<'a> {
     path: String,
-    captures: Vec<String>,
+    captures: Vec<Value>,
+    pattern: &'a GlobPattern,
 }
 
-impl<'a> From<&'a Entry> for GlobVar {
-    fn from(e: &Entry) -> GlobVar {
+impl<'a> GlobVar<'a> {
+    pub fn new(e: &Entry, pattern: &'a GlobPattern) -> GlobVar<'a> {
         let path = e.path().to_str().expect("path is utf8").to_string();
         let mut captures = Vec::new();
-        for i in 0..10 {
-            match e.group(i) {
-                Some(x) => {
-                    captures.push(x.to_str().expect("path is utf8")
-                        .to_string());
-                }
-                None => break,
-            }
+        while let Some(x) = e.group(captures.len()) {
+            captures.push(Value(x.to_str().expect("path is utf8")
+                .to_string()));
+        }
+        return GlobVar { path, captures, pattern }
+    }
+    /// Variable with placeholders instead of captured values
+    ///
+    /// Used to check that patterns refer only to existing groups, so the
+    /// placeholder is a value that passes every validator and filter.
+    pub fn example(pattern: &'a GlobPattern) -> GlobVar<'a> {
+        let captures = (0..pattern.groups()+1)
+            .map(|_| Value(String::from(EXAMPLE)))
+            .collect();
+        GlobVar {
+            path: String::from(EXAMPLE),
+            captures, pattern,
         }
-        return GlobVar { path, captures }
     }
 }
 
-impl<'render> Variable<'render> for GlobVar {
+impl<'a, 'render> Variable<'render> for GlobVar<'a> {
     fn typename(&self) -> &'static str {
         "GlobVar"
     }
@@ -33,6 +48,19 @@ impl<'render> Variable<'render> for GlobVar {
         Ok((&self.path).into())
     }
 
+    fn attr<'x>(&'x self, attr: &str)
+        -> Result<Var<'x, 'render>, DataError>
+        where 'render: 'x
+    {
+        match self.pattern.group_index(attr)
+            .and_then(|idx| self.captures.get(idx))
+        {
+            Some(x) => Ok(Var::borrow(x)),
+            None => Err(DataError::VariableNotFound(format!(
+                "group {:?} in pattern {:?}", attr, self.pattern.to_string()))),
+        }
+    }
+
     fn index<'x>(&'x self, key: &(Variable<'render> + 'render))
         -> Result<Var<'x, 'render>, DataError>
         where 'render: 'x
@@ -40,7 +68,8 @@ impl<'render> Variable<'render> for GlobVar {
         let index = key.as_int_key()?;
         match self.captures.get(index) {
             Some(x) => Ok(Var::borrow(x)),
-            None => Ok(Var::undefined()),
+            None => Err(DataError::VariableNotFound(format!(
+                "group {} in pattern {:?}", index, self.pattern.to_string()))),
         }
     }
 }
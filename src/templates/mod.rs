@@ -1,10 +1,17 @@
 use trimmer::{Parser, Template};
 
+mod condition;
+mod glob_pattern;
 mod glob_var;
 mod pattern;
+mod validators;
+mod value;
 
+pub use self::condition::Condition;
+pub use self::glob_pattern::GlobPattern;
 pub use self::glob_var::GlobVar;
 pub use self::pattern::Pattern;
+pub use self::value::{Value, values};
 
 lazy_static! {
     /// This holds parser so we don't need to compile it's complex regexes
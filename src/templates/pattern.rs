@@ -1,9 +1,11 @@
 use std::fmt;
+use failure::Error;
 use trimmer::{Template, Options, Output, DataError, Variable};
 use trimmer::{Context, RenderError};
 use serde::de::{self, Visitor, Deserializer, Deserialize};
 
 use templates::PARSER;
+use templates::validators::PREAMBLE;
 
 
 #[derive(Debug)]
@@ -16,8 +18,29 @@ struct PatternVisitor;
 
 
 impl Pattern {
-    pub fn render(&self, ctx: &Context) -> Result<String, RenderError> {
+    /// Renders pattern, error refers to the config key the pattern is at
+    pub fn render_key(&self, key: &str, ctx: &Context)
+        -> Result<String, Error>
+    {
         self.ast.render(ctx)
+            .map_err(strip_preamble)
+            .map_err(|e| format_err!("can't render {} ({:?}): {}",
+                key, self.text, e))
+    }
+}
+
+/// Fixes line numbers in errors, so they point into the pattern itself
+/// rather than into the `PREAMBLE` prepended to it
+fn strip_preamble(err: RenderError) -> RenderError {
+    let lines = PREAMBLE.matches('\n').count();
+    match err {
+        RenderError::Data(errs) => {
+            RenderError::Data(errs.into_iter().map(|(mut pos, e)| {
+                pos.line = pos.line.saturating_sub(lines);
+                (pos, e)
+            }).collect())
+        }
+        err => err,
     }
 }
 
@@ -32,10 +55,15 @@ impl<'de> Visitor<'de> for PatternVisitor {
         where E: de::Error
     {
         let options = Options::new()
-            // TODO(tailhook) add validators
             .syntax_oneline()
             .clone();
-        match PARSER.parse_with_options(&options, v) {
+        // validators are only looked up when rendering, so syntax errors
+        // are reported for the text alone to get positions right
+        if let Err(e) = PARSER.parse_with_options(&options, v) {
+            return Err(E::custom(e));
+        }
+        let text = format!("{}{}", PREAMBLE, v);
+        match PARSER.parse_with_options(&options, &text) {
             Ok(tpl) => {
                 Ok(Pattern {
                     text: v.to_string(),
@@ -66,3 +94,50 @@ impl<'render> Variable<'render> for Pattern {
         Ok((&self.text).into())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use serde_json::from_str;
+    use serde_json::to_string;
+    use trimmer::Context;
+
+    use templates::Value;
+    use super::Pattern;
+
+    fn render(text: &str, region: &str) -> Result<String, String> {
+        let mut vars = HashMap::new();
+        vars.insert("region".to_string(), Value(region.to_string()));
+        let mut ctx = Context::new();
+        ctx.set("vars", &vars);
+        let pattern: Pattern = from_str(&to_string(text).unwrap())
+            .map_err(|e| e.to_string())?;
+        pattern.render_key("key", &ctx).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn validators() {
+        assert_eq!(render("{{ vars.region | dns_label }}", "eu-1").unwrap(),
+                   "eu-1");
+        assert!(render("{{ vars.region | dns_label }}", "eu.1").is_err());
+        assert_eq!(render("{{ vars.region | shell_quote }}", "a b").unwrap(),
+                   "'a b'");
+    }
+
+    #[test]
+    fn default_value() {
+        let text = r#"{{ vars.region or "eu" }}"#;
+        assert_eq!(render(text, "us").unwrap(), "us");
+        assert_eq!(render(text, "").unwrap(), "eu");
+        assert_eq!(render(r#"{{ vars.zone or "a" }}"#, "us").unwrap(), "a");
+    }
+
+    #[test]
+    fn transformations() {
+        assert_eq!(render("{{ vars.region.uppercase }}", "eu").unwrap(),
+                   "EU");
+        let err = render("{{ vars.region.upercase }}", "eu").unwrap_err();
+        assert!(err.contains("upercase"), "{}", err);
+    }
+}
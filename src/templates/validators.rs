@@ -0,0 +1,21 @@
+/// Validators and filters available in every pattern in destination config
+///
+/// Used as `{{ vars.cluster | hostname }}`, validation error is reported
+/// if value doesn't match. Names use underscores as trimmer requires.
+///
+/// * `hostname` -- a (possibly fully qualified) host name
+/// * `dns_label` -- a single component of the host name
+/// * `slug` -- lowercase alphanumerics separated by dashes or underscores
+/// * `path` -- a path without quotes, backslashes and newlines
+/// * `shell_quote` -- escapes value as a single shell argument
+///
+/// Default value is set with `or`, i.e. `{{ vars.region or "eu" }}` renders
+/// `eu` if variable is empty or not defined.
+pub static PREAMBLE: &str = concat!(
+    r"## validate hostname: ^[a-zA-Z0-9]([a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?",
+        r"(\.[a-zA-Z0-9]([a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?)*\.?$", "\n",
+    r"## validate dns_label: ^[a-z0-9]([a-z0-9-]{0,61}[a-z0-9])?$", "\n",
+    r"## validate slug: ^[a-z0-9]+([_-][a-z0-9]+)*$", "\n",
+    r#"## validate path: ^[^'"\r\n\\]+$"#, "\n",
+    "## filter shell_quote: builtin.quoted_shell_argument\n",
+);
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use trimmer::{Variable, Output, Var, DataError, Comparable};
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+
+/// A string value which has transformations as attributes
+///
+/// * `x.lowercase`, `x.uppercase` -- change case
+/// * `x.url_escape` -- percent-encode to use as url path segment or query
+/// * `x.sha_short` -- abbreviated commit hash, i.e. `abcdef1` from
+///   `v1.2.3-4-gabcdef1-dirty` (git-describe version), or first seven
+///   characters of the value if it isn't a git-describe version
+/// * `x.slug` -- lowercased value with every char that isn't alphanumeric
+///   replaced by `-`
+#[derive(Debug, Clone)]
+pub struct Value(pub String);
+
+
+fn sha_short(val: &str) -> String {
+    for part in val.split('-').skip(1) {
+        if part.len() > 1 && part.starts_with('g') &&
+            part[1..].chars().all(|c| c.is_digit(16))
+        {
+            return part[1..].chars().take(7).collect();
+        }
+    }
+    val.chars().take(7).collect()
+}
+
+fn slug(val: &str) -> String {
+    let mut res = String::with_capacity(val.len());
+    for c in val.chars() {
+        if c.is_ascii() && c.is_alphanumeric() {
+            res.push(c.to_ascii_lowercase());
+        } else if !res.ends_with('-') {
+            res.push('-');
+        }
+    }
+    res.trim_matches('-').to_string()
+}
+
+impl<'render> Variable<'render> for Value {
+    fn typename(&self) -> &'static str {
+        "str"
+    }
+    fn attr<'x>(&'x self, attr: &str)
+        -> Result<Var<'x, 'render>, DataError>
+        where 'render: 'x
+    {
+        let val = match attr {
+            "lowercase" => self.0.to_lowercase(),
+            "uppercase" => self.0.to_uppercase(),
+            "url_escape" => {
+                utf8_percent_encode(&self.0, PATH_SEGMENT_ENCODE_SET)
+                .to_string()
+            }
+            "sha_short" => sha_short(&self.0),
+            "slug" => slug(&self.0),
+            _ => {
+                // AttrNotFound would render as empty string silently
+                return Err(DataError::VariableNotFound(format!(
+                    "transformation {:?} of a string", attr)));
+            }
+        };
+        Ok(Var::owned(Value(val)))
+    }
+    fn output(&self) -> Result<Output, DataError> {
+        Ok((&self.0).into())
+    }
+    fn as_str_key<'x>(&'x self) -> Result<&'x str, DataError> {
+        Ok(&self.0)
+    }
+    fn as_bool(&self) -> Result<bool, DataError> {
+        Ok(self.0.len() > 0)
+    }
+    fn as_comparable(&self) -> Result<Comparable, DataError> {
+        Ok(self.0[..].into())
+    }
+}
+
+/// Converts variables to values, so transformations are available
+pub fn values(vars: &HashMap<String, String>) -> HashMap<String, Value> {
+    vars.iter().map(|(k, v)| (k.clone(), Value(v.clone()))).collect()
+}